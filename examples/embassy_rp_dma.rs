@@ -0,0 +1,36 @@
+//! Upload buffers of frames to two motors at once by DMA, with embassy-rp.
+//!
+//! Each motor gets its own DMA channel, paced by the TX FIFO of the state machine
+//! driving it, so every word of a buffer is sent as its own DShot frame.
+
+#![no_std]
+#![no_main]
+
+use dshot_pio::dshot_embassy_rp::{DshotPio, DshotSpeed, DshotValue};
+use embassy_executor::Spawner;
+use embassy_rp::{bind_interrupts, clocks::clk_sys_freq, peripherals::PIO0, pio::InterruptHandler, Peripheral};
+use panic_halt as _;
+
+bind_interrupts!(struct Pio0Irqs {
+    PIO0_IRQ_0 => InterruptHandler<PIO0>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+
+    // Motor 0 is driven by state machine 0, and motor 1 by state machine 1
+    let pins = (p.PIN_14, p.PIN_15);
+    let mut dshot = DshotPio::<2, _>::with_speed(p.PIO0, Pio0Irqs, pins, DshotSpeed::Dshot600, clk_sys_freq()).unwrap();
+
+    // Motor 0 ramps up, while motor 1 holds its throttle
+    let mut ramp = [0; 64];
+    for (i, word) in ramp.iter_mut().enumerate() {
+        *word = dshot.encode(DshotValue::Throttle(100 + 10 * i as u16), false);
+    }
+    let hold = [dshot.encode(DshotValue::Throttle(300), false); 64];
+
+    // Both transfers run alongside each other, completing once both buffers are sent
+    let channels = [p.DMA_CH0.into_ref().map_into(), p.DMA_CH1.into_ref().map_into()];
+    dshot.dma_push(channels, [&ramp, &hold]).await;
+}
@@ -0,0 +1,74 @@
+//! Upload buffers of frames to two motors at once by DMA, with rp2040-hal.
+//!
+//! Each motor gets its own DMA channel, paced by the TX FIFO of the state machine
+//! driving it, so every word of a buffer is sent as its own DShot frame.
+
+#![no_std]
+#![no_main]
+
+use cortex_m::singleton;
+use cortex_m_rt::entry;
+use panic_halt as _;
+
+use dshot_pio::dshot_rp2040_hal::{DshotPio, DshotSpeed, DshotValue};
+use rp2040_hal::{
+    clocks::init_clocks_and_plls,
+    dma::DMAExt,
+    gpio::Pins,
+    pac,
+    pio::{SM0, SM1},
+    Sio, Watchdog,
+};
+
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+
+const XTAL_FREQ_HZ: u32 = 12_000_000;
+
+#[entry]
+fn main() -> ! {
+    let mut pac = pac::Peripherals::take().unwrap();
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+    let clocks = init_clocks_and_plls(
+        XTAL_FREQ_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+    let sio = Sio::new(pac.SIO);
+    let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+
+    // Motor 0 is driven by state machine 0, and motor 1 by state machine 1
+    let pins = (pins.gpio14, pins.gpio15);
+    let mut dshot = DshotPio::<2, _>::with_speed(pac.PIO0, &mut pac.RESETS, pins, DshotSpeed::Dshot600, &clocks).unwrap();
+    let dma = pac.DMA.split(&mut pac.RESETS);
+
+    // Motor 0 ramps up, while motor 1 holds its throttle
+    let ramp = singleton!(: [u32; 64] = [0; 64]).unwrap();
+    for (i, word) in ramp.iter_mut().enumerate() {
+        *word = dshot.encode(DshotValue::Throttle(100 + 10 * i as u16), false);
+    }
+    let hold = singleton!(: [u32; 64] = [0; 64]).unwrap();
+    hold.fill(dshot.encode(DshotValue::Throttle(300), false));
+
+    // Both transfers run alongside each other, and the driver
+    // is borrowed until both of them have been waited for
+    let mut targets = dshot.dma_targets();
+    let ramping = targets.dma_push::<SM0, _, _>(dma.ch0, ramp);
+    let holding = targets.dma_push::<SM1, _, _>(dma.ch1, hold);
+    let (ch0, ramp, _) = ramping.wait();
+    let (_ch1, _hold, _) = holding.wait();
+
+    // A single motor can also be given a buffer straight from the driver
+    let (_ch0, _ramp, _) = dshot.dma_push::<SM0, _, _>(ch0, ramp).wait();
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
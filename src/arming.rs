@@ -0,0 +1,280 @@
+//! Refusing throttle until the motors are explicitly armed.
+//!
+//! ESCs only arm after receiving motor stop frames for a while, and nothing
+//! in a bare driver stops full throttle from being sent right after it is
+//! built. The arming wrapper starts out disarmed, and `tick` is called from a
+//! timer at the DShot update rate, sending motor stop until the wrapper is
+//! armed. Arming takes an explicit `arm`, after which throttle is accepted
+//! once the arming window has passed, with motor stop sent meanwhile. Until then,
+//! throttle sent through `DshotPioTrait` is replaced by motor stop, while the
+//! `checked_*` methods refuse it with `DshotError::Disarmed`.
+
+use super::telemetry::Telemetry;
+use super::{DshotCommand, DshotError, DshotPioTrait, DshotSync, DshotValue};
+
+/// Arming progress of the motors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArmingState {
+    /// Motor stop is sent, and throttle is refused
+    Disarmed,
+    /// Armed through `arm`, but motor stop has not been sent for the whole arming window yet
+    Arming,
+    /// Throttle is accepted
+    Armed,
+}
+
+/// Wraps a driver of N motors, see the module documentation
+pub struct Arming<D: DshotPioTrait<N>, const N: usize> {
+    dshot: D,
+    window_us: u64,
+    /// When `arm` was called, from which motor stop is sent for the arming window
+    armed_at_us: Option<u64>,
+    state: ArmingState,
+}
+
+impl<D: DshotPioTrait<N>, const N: usize> Arming<D, N> {
+    /// Wrap a driver, which must receive motor stop for `window_us` microseconds before it is armed
+    pub fn new(dshot: D, window_us: u64) -> Arming<D, N> {
+        Arming {
+            dshot,
+            window_us,
+            armed_at_us: None,
+            state: ArmingState::Disarmed,
+        }
+    }
+
+    /// Send motor stop unless armed, given the current time in microseconds, and finish arming
+    /// once the arming window has passed since `arm`. Should be called regularly, from a timer
+    /// at the DShot update rate. Returns the arming state.
+    pub fn tick(&mut self, now_us: u64) -> ArmingState {
+        if self.state == ArmingState::Armed {
+            return self.state;
+        }
+
+        self.stop();
+        if let Some(armed_at_us) = self.armed_at_us {
+            if now_us.saturating_sub(armed_at_us) >= self.window_us {
+                self.state = ArmingState::Armed;
+            }
+        }
+        self.state
+    }
+
+    /// Arm the motors, given the current time in microseconds. Throttle is accepted from the
+    /// first tick at least the arming window later, with motor stop sent by every tick until then.
+    pub fn arm(&mut self, now_us: u64) {
+        if self.state == ArmingState::Disarmed {
+            self.state = ArmingState::Arming;
+            self.armed_at_us = Some(now_us);
+        }
+    }
+
+    /// Current arming state
+    pub fn state(&self) -> ArmingState {
+        self.state
+    }
+
+    /// Whether throttle is accepted
+    pub fn is_armed(&self) -> bool {
+        self.state == ArmingState::Armed
+    }
+
+    /// The wrapped driver
+    pub fn inner(&mut self) -> &mut D {
+        &mut self.dshot
+    }
+
+    /// Give back the wrapped driver
+    pub fn into_inner(self) -> D {
+        self.dshot
+    }
+
+    /// Like `command`, but refusing throttle values unless armed, in which case nothing is sent to any motor
+    pub fn checked_command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) -> Result<(), DshotError> {
+        if command.iter().any(|value| matches!(value, DshotValue::Throttle(_))) {
+            self.check_armed()?;
+        }
+        self.dshot.command(command, telemetry);
+        Ok(())
+    }
+
+    /// Like `throttle_clamp`, but refused unless armed
+    pub fn checked_throttle_clamp(&mut self, throttle: [u16; N]) -> Result<(), DshotError> {
+        self.check_armed()?;
+        self.dshot.throttle_clamp(throttle);
+        Ok(())
+    }
+
+    /// Like `throttle_clamp_telemetry`, but refused unless armed
+    pub fn checked_throttle_clamp_telemetry(&mut self, throttle: [u16; N], telemetry: [bool; N]) -> Result<(), DshotError> {
+        self.check_armed()?;
+        self.dshot.throttle_clamp_telemetry(throttle, telemetry);
+        Ok(())
+    }
+
+    /// Like `throttle_minimum`, which stops the motors and so is never refused.
+    /// Motor stop is sent instead unless armed.
+    pub fn checked_throttle_minimum(&mut self) -> Result<(), DshotError> {
+        DshotPioTrait::throttle_minimum(self);
+        Ok(())
+    }
+
+    fn check_armed(&self) -> Result<(), DshotError> {
+        match self.state {
+            ArmingState::Armed => Ok(()),
+            _ => Err(DshotError::Disarmed),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.dshot.command([DshotCommand::MotorStop.into(); N], [false; N]);
+    }
+}
+
+impl<D: DshotPioTrait<N> + DshotSync, const N: usize> Arming<D, N> {
+    /// Disarm the motors. Frames still queued in the TX FIFOs are dropped, and motor
+    /// stop is sent right away. The arming window starts over.
+    pub fn disarm(&mut self) {
+        self.state = ArmingState::Disarmed;
+        self.armed_at_us = None;
+        self.dshot.halt();
+        self.dshot.start();
+        self.stop();
+    }
+}
+
+impl<D: DshotPioTrait<N>, const N: usize> DshotPioTrait<N> for Arming<D, N> {
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry.
+    /// Unless armed, throttle values are replaced by motor stop.
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) {
+        let command = match self.is_armed() {
+            true => command,
+            false => command.map(|value| match value {
+                DshotValue::Throttle(_) => DshotCommand::MotorStop.into(),
+                DshotValue::Command(_) => value,
+            }),
+        };
+        self.dshot.command(command, telemetry);
+    }
+
+    /// Send a special command to each motor, motors given `None` are left alone.
+    /// Special commands are sent whether armed or not.
+    fn send_command(&mut self, command: [Option<DshotCommand>; N]) {
+        self.dshot.send_command(command);
+    }
+
+    /// Set the direction of rotation for each motor
+    fn reverse(&mut self, reverse: [bool; N]) {
+        self.dshot.reverse(reverse);
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    fn extended_telemetry(&mut self, enable: [bool; N]) {
+        self.dshot.extended_telemetry(enable);
+    }
+
+    /// Set the throttle for each motor, clamped between 48 and 2047. Motor stop is sent unless armed.
+    fn throttle_clamp(&mut self, throttle: [u16; N]) {
+        match self.is_armed() {
+            true => self.dshot.throttle_clamp(throttle),
+            false => self.stop(),
+        }
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors.
+    /// Motor stop is sent unless armed.
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16; N], telemetry: [bool; N]) {
+        match self.is_armed() {
+            true => self.dshot.throttle_clamp_telemetry(throttle, telemetry),
+            false => self.dshot.command([DshotCommand::MotorStop.into(); N], telemetry),
+        }
+    }
+
+    /// Set the throttle for each motor to zero (DShot command 48). Motor stop is sent unless armed.
+    fn throttle_minimum(&mut self) {
+        match self.is_armed() {
+            true => self.dshot.throttle_minimum(),
+            false => self.stop(),
+        }
+    }
+
+    fn erpm(&mut self) -> [Option<u32>; N] {
+        self.dshot.erpm()
+    }
+
+    fn telemetry(&mut self) -> [Option<Telemetry>; N] {
+        self.dshot.telemetry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{command, throttle, MockDshot, STOP};
+
+    #[test]
+    fn replaces_throttle_with_motor_stop_until_armed() {
+        let mut dshot = Arming::new(MockDshot::<2>::new(), 1_000);
+        dshot.throttle_clamp([2000; 2]);
+        assert_eq!(dshot.inner().last(), [STOP; 2]);
+        dshot.command([DshotValue::Throttle(2000), DshotCommand::Beep1.into()], [false, true]);
+        assert_eq!(dshot.inner().last(), [STOP, command(DshotCommand::Beep1)]);
+
+        dshot.arm(0);
+        assert_eq!(dshot.tick(0), ArmingState::Arming);
+        dshot.throttle_minimum();
+        assert_eq!(dshot.inner().last(), [STOP; 2]);
+
+        assert_eq!(dshot.tick(1_000), ArmingState::Armed);
+        dshot.throttle_clamp([2000; 2]);
+        assert_eq!(dshot.inner().last(), [throttle(2000); 2]);
+    }
+
+    #[test]
+    fn checked_methods_refuse_throttle_until_armed() {
+        let mut dshot = Arming::new(MockDshot::<1>::new(), 0);
+        assert_eq!(dshot.checked_throttle_clamp([2000]), Err(DshotError::Disarmed));
+        assert_eq!(dshot.checked_command([DshotValue::Throttle(2000)], [false]), Err(DshotError::Disarmed));
+        assert!(dshot.inner().sent.is_empty());
+        assert_eq!(dshot.checked_command([DshotCommand::Beep1.into()], [true]), Ok(()));
+        assert_eq!(dshot.checked_throttle_minimum(), Ok(()));
+        assert_eq!(dshot.inner().last(), [STOP]);
+
+        dshot.arm(0);
+        dshot.tick(0);
+        assert_eq!(dshot.checked_throttle_clamp([2000]), Ok(()));
+        assert_eq!(dshot.inner().last(), [throttle(2000)]);
+    }
+
+    #[test]
+    fn arming_window_runs_from_arm() {
+        let mut dshot = Arming::new(MockDshot::<1>::new(), 1_000);
+        assert_eq!(dshot.tick(0), ArmingState::Disarmed);
+        assert_eq!(dshot.tick(5_000), ArmingState::Disarmed);
+
+        // Ticks before arming do not count towards the window
+        dshot.arm(5_000);
+        assert_eq!(dshot.tick(5_999), ArmingState::Arming);
+        assert_eq!(dshot.tick(6_000), ArmingState::Armed);
+    }
+
+    #[test]
+    fn disarm_drops_queued_frames() {
+        let mut dshot = Arming::new(MockDshot::<2>::new(), 1_000);
+        dshot.arm(0);
+        dshot.tick(1_000);
+        dshot.throttle_clamp([2000; 2]);
+
+        let sent = dshot.inner().sent.len();
+        dshot.disarm();
+        assert_eq!(dshot.state(), ArmingState::Disarmed);
+        assert_eq!(dshot.inner().restarts, 1);
+        // Motor stop is the first frame sent once the state machines are started again
+        assert_eq!(dshot.inner().sent[sent..], [[STOP; 2]]);
+
+        // The arming window starts over
+        dshot.arm(1_500);
+        assert_eq!(dshot.tick(2_000), ArmingState::Arming);
+        assert_eq!(dshot.tick(2_500), ArmingState::Armed);
+    }
+}
@@ -0,0 +1,96 @@
+//! Special DShot commands, sent using the values 0 to 47.
+//!
+//! ESCs only act on a command when the telemetry request bit is set, so
+//! command frames are always encoded with it.
+
+use super::{command_frame, DshotValue};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DshotCommand {
+    MotorStop = 0,
+    Beep1 = 1,
+    Beep2 = 2,
+    Beep3 = 3,
+    Beep4 = 4,
+    Beep5 = 5,
+    EscInfo = 6,
+    SpinDirection1 = 7,
+    SpinDirection2 = 8,
+    Mode3dOff = 9,
+    Mode3dOn = 10,
+    SettingsRequest = 11,
+    SaveSettings = 12,
+    ExtendedTelemetryEnable = 13,
+    ExtendedTelemetryDisable = 14,
+    SpinDirectionNormal = 20,
+    SpinDirectionReversed = 21,
+    Led0On = 22,
+    Led1On = 23,
+    Led2On = 24,
+    Led3On = 25,
+    Led0Off = 26,
+    Led1Off = 27,
+    Led2Off = 28,
+    Led3Off = 29,
+    AudioStreamModeToggle = 30,
+    SilentModeToggle = 31,
+    SignalLineTelemetryDisable = 32,
+    SignalLineTelemetryEnable = 33,
+    SignalLineContinuousErpmTelemetry = 34,
+    SignalLineContinuousErpmPeriodTelemetry = 35,
+    SignalLineTemperatureTelemetry = 42,
+    SignalLineVoltageTelemetry = 43,
+    SignalLineCurrentTelemetry = 44,
+    SignalLineConsumptionTelemetry = 45,
+    SignalLineErpmTelemetry = 46,
+    SignalLineErpmPeriodTelemetry = 47,
+}
+
+impl DshotCommand {
+    /// Number of times the command must be sent in a row before the ESC acts on it
+    pub fn repeats(self) -> u8 {
+        match self {
+            DshotCommand::SpinDirection1
+            | DshotCommand::SpinDirection2
+            | DshotCommand::Mode3dOff
+            | DshotCommand::Mode3dOn
+            | DshotCommand::SaveSettings
+            | DshotCommand::ExtendedTelemetryEnable
+            | DshotCommand::ExtendedTelemetryDisable
+            | DshotCommand::SpinDirectionNormal
+            | DshotCommand::SpinDirectionReversed
+            | DshotCommand::SignalLineTelemetryDisable
+            | DshotCommand::SignalLineTelemetryEnable
+            | DshotCommand::SignalLineContinuousErpmTelemetry
+            | DshotCommand::SignalLineContinuousErpmPeriodTelemetry => 6,
+            _ => 1,
+        }
+    }
+
+    /// Time in microseconds the ESC needs after the command, before it accepts another one
+    pub fn delay_after_us(self) -> u32 {
+        match self {
+            DshotCommand::Beep1
+            | DshotCommand::Beep2
+            | DshotCommand::Beep3
+            | DshotCommand::Beep4
+            | DshotCommand::Beep5 => 260_000,
+            DshotCommand::EscInfo => 12_000,
+            DshotCommand::SaveSettings => 35_000,
+            _ => 0,
+        }
+    }
+
+    /// Encode the complete command frame, with the telemetry request bit set
+    #[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+    pub(crate) fn frame(self) -> u16 {
+        command_frame(self as u8)
+    }
+}
+
+impl From<DshotCommand> for DshotValue {
+    fn from(command: DshotCommand) -> DshotValue {
+        DshotValue::Command(command as u8)
+    }
+}
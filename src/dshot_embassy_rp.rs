@@ -1,60 +1,269 @@
-use dshot_encoder as dshot;
-pub use super::DshotPioTrait;
+use super::encoder as dshot;
+use core::marker::PhantomData;
+pub use super::{DshotPioTrait, DshotPioAsync, DshotPioTry, DshotSync, DshotLocate, DshotCommand, DshotError, DshotMode, DshotSpeed, DshotValue};
+use super::telemetry::Telemetry;
+use super::{fifo, parallel};
 
+use fixed::{types::extra::U8, FixedU32};
 use embassy_rp::{
-    pio::{ Instance, Pio, Config, PioPin, ShiftConfig, ShiftDirection::Left, InterruptHandler},
-    Peripheral, interrupt::typelevel::Binding
+    gpio::Pull,
+    pio::{ Instance, Pio, Common, Config, FifoJoin, LoadedProgram, Pin, PioPin, ShiftConfig, ShiftDirection::{Left, Right}, InterruptHandler, StateMachine},
+    dma::{AnyChannel, Transfer},
+    interrupt::{self, typelevel::Binding},
+    pac, Peripheral, PeripheralRef,
 };
+use ::pio::{InstructionOperands, MovDestination, MovOperation, MovSource, SetDestination};
 #[allow(dead_code)]
-pub struct DshotPio<'a, const N : usize, PIO : Instance> {
-    pio_instance: Pio<'a,PIO>,
+pub struct DshotPio<'a, const N : usize, PIO : Instance, S = ()> {
+    // Only owned when the driver was given the whole PIO block
+    common: Option<Common<'a, PIO>>,
+    // State machines not given to the driver are left out
+    sm0: Option<StateMachine<'a, PIO, 0>>,
+    sm1: Option<StateMachine<'a, PIO, 1>>,
+    sm2: Option<StateMachine<'a, PIO, 2>>,
+    sm3: Option<StateMachine<'a, PIO, 3>>,
+    /// Index of the state machine driving each motor
+    state_machines: [usize; N],
+    configs: [Config<'a, PIO>; N],
+    pins: [Pin<'a, PIO>; N],
+    /// GPIO number of each pin, relative to the GPIO base of the PIO block
+    gpio: [u8; N],
+    program: LoadedProgram<'a, PIO>,
+    mode: DshotMode,
+    /// Tuple of state machines given to a driver sharing its PIO block, `()` if given the whole block
+    given: PhantomData<fn() -> S>,
 }
 
+/// Evaluate `$body` with `$sm` bound to state machine `$index` of the driver
+macro_rules! with_sm {
+    ($dshot:expr, $index:expr, |$sm:ident| $body:expr) => {
+        match $index {
+            0 => { let $sm = $dshot.sm0.as_mut().expect("State machine does not drive a motor"); $body }
+            1 => { let $sm = $dshot.sm1.as_mut().expect("State machine does not drive a motor"); $body }
+            2 => { let $sm = $dshot.sm2.as_mut().expect("State machine does not drive a motor"); $body }
+            3 => { let $sm = $dshot.sm3.as_mut().expect("State machine does not drive a motor"); $body }
+            _ => unreachable!("PIO blocks only have four state machines"),
+        }
+    };
+}
+
+/// Parts of a PIO block given back by `DshotPio::free`
+pub type DshotPioParts<'a, PIO> = (Common<'a, PIO>, StateMachine<'a, PIO, 0>, StateMachine<'a, PIO, 1>, StateMachine<'a, PIO, 2>, StateMachine<'a, PIO, 3>);
+
+/// Pins to output DShot on, given as a tuple of up to eight pins, or an array of pins of the same type
+pub trait DshotPins<'a, PIO: Instance, const N: usize> {
+    fn make_pio_pins(self, common: &mut Common<'a, PIO>) -> [Pin<'a, PIO>; N];
+}
+
+impl <'a, PIO: Instance, P: PioPin, const N: usize> DshotPins<'a, PIO, N> for [P; N] {
+    fn make_pio_pins(self, common: &mut Common<'a, PIO>) -> [Pin<'a, PIO>; N] {
+        self.map(|pin| common.make_pio_pin(pin))
+    }
+}
+
+macro_rules! impl_dshot_pins {
+    ($n:literal, $($p:ident $i:tt),+) => {
+        impl <'a, PIO: Instance, $($p: PioPin),+> DshotPins<'a, PIO, $n> for ($($p,)+) {
+            fn make_pio_pins(self, common: &mut Common<'a, PIO>) -> [Pin<'a, PIO>; $n] {
+                [$(common.make_pio_pin(self.$i)),+]
+            }
+        }
+    };
+}
+
+impl_dshot_pins!(1, P0 0);
+impl_dshot_pins!(2, P0 0, P1 1);
+impl_dshot_pins!(3, P0 0, P1 1, P2 2);
+impl_dshot_pins!(4, P0 0, P1 1, P2 2, P3 3);
+impl_dshot_pins!(5, P0 0, P1 1, P2 2, P3 3, P4 4);
+impl_dshot_pins!(6, P0 0, P1 1, P2 2, P3 3, P4 4, P5 5);
+impl_dshot_pins!(7, P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6);
+impl_dshot_pins!(8, P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7);
+
+/// State machines to drive DShot with, given as a tuple of one to four `StateMachine`s
+/// of the same PIO block, in increasing order. Motor `i` is driven by the `i`th state machine.
+/// Sealed, as only the tuples implementing it here can be handed over to a driver.
+pub trait DshotStateMachines<'a, PIO: Instance, const N: usize>: sealed::HandOver<'a, PIO, N> {}
+
+mod sealed {
+    use super::{DshotPio, Instance};
+
+    /// Moving state machines in and out of a driver, out of reach outside the crate
+    pub trait HandOver<'a, PIO: Instance, const N: usize>: Sized {
+        /// Hand the state machines over to the driver
+        fn hand_over(self, dshot: &mut DshotPio<'a, N, PIO, Self>);
+        /// Take the state machines back from the driver
+        fn take_back(dshot: &mut DshotPio<'a, N, PIO, Self>) -> Self;
+    }
+}
+
+macro_rules! impl_dshot_state_machines {
+    ($n:literal, $($field:ident $index:literal $i:tt),+) => {
+        impl <'a, PIO: Instance> DshotStateMachines<'a, PIO, $n> for ($(StateMachine<'a, PIO, $index>,)+) {}
+
+        impl <'a, PIO: Instance> sealed::HandOver<'a, PIO, $n> for ($(StateMachine<'a, PIO, $index>,)+) {
+            fn hand_over(self, dshot: &mut DshotPio<'a, $n, PIO, Self>) {
+                $(dshot.$field = Some(self.$i);)+
+                dshot.state_machines = [$($index),+];
+            }
+
+            fn take_back(dshot: &mut DshotPio<'a, $n, PIO, Self>) -> Self {
+                ($(dshot.$field.take().expect("State machine does not drive a motor"),)+)
+            }
+        }
+    };
+}
+
+impl_dshot_state_machines!(1, sm0 0 0);
+impl_dshot_state_machines!(1, sm1 1 0);
+impl_dshot_state_machines!(1, sm2 2 0);
+impl_dshot_state_machines!(1, sm3 3 0);
+impl_dshot_state_machines!(2, sm0 0 0, sm1 1 1);
+impl_dshot_state_machines!(2, sm0 0 0, sm2 2 1);
+impl_dshot_state_machines!(2, sm0 0 0, sm3 3 1);
+impl_dshot_state_machines!(2, sm1 1 0, sm2 2 1);
+impl_dshot_state_machines!(2, sm1 1 0, sm3 3 1);
+impl_dshot_state_machines!(2, sm2 2 0, sm3 3 1);
+impl_dshot_state_machines!(3, sm0 0 0, sm1 1 1, sm2 2 2);
+impl_dshot_state_machines!(3, sm0 0 0, sm1 1 1, sm3 3 2);
+impl_dshot_state_machines!(3, sm0 0 0, sm2 2 1, sm3 3 2);
+impl_dshot_state_machines!(3, sm1 1 0, sm2 2 1, sm3 3 2);
+impl_dshot_state_machines!(4, sm0 0 0, sm1 1 1, sm2 2 2, sm3 3 3);
+
+
+/// Load the DShot program for the given mode into the PIO block
+fn load_program<'a,PIO: Instance>  (
+    common: &mut Common<'a, PIO>,
+    mode: DshotMode,
+) -> LoadedProgram<'a, PIO> {
+    match mode {
+        DshotMode::Normal => {
+            // Define program
+            let dshot_pio_program = pio_proc::pio_asm!(
+                "set pindirs, 1",
+                "entry:"
+                "   pull"
+                "   out null 16"
+                "   set x 15"
+                "loop:"
+                "   set pins 1"
+                "   out y 1"
+                "   jmp !y zero"
+                "   nop [2]"
+                "one:" // 6 and 2
+                "   set pins 0"
+                "   jmp x-- loop"
+                "   jmp reset"
+                "zero:" // 3 and 5
+                "   set pins 0 [3]"
+                "   jmp x-- loop"
+                "   jmp reset"
+                "reset:" // Blank frame
+                "   nop [31]"
+                "   nop [31]"
+                "   nop [31]"
+                "   jmp entry [31]"
+            );
+            common.load_program(&dshot_pio_program.program)
+        }
+        DshotMode::Repeat { .. } => {
+            // Same timing as normal mode, but X keeps the last word, which `pull noblock`
+            // reloads whenever the TX FIFO is empty. The end of the frame is found from the
+            // output shift count instead, leaving Y for the bits and the blanking loop.
+            let dshot_pio_program = pio_proc::pio_asm!(
+                "set pindirs, 1",
+                "entry:"
+                "   pull noblock"
+                "   mov x osr"
+                "   out isr 16" // Upper half-word is the blanking loop count
+                "loop:"
+                "   set pins 1"
+                "   out y 1"
+                "   jmp !y zero"
+                "   nop [2]"
+                "one:" // 6 and 2
+                "   set pins 0"
+                "   jmp !osre loop"
+                "   jmp reset"
+                "zero:" // 3 and 5
+                "   set pins 0 [3]"
+                "   jmp !osre loop"
+                "reset:" // Blank frame
+                "   mov y isr [3]"
+                "blank:"
+                "   jmp y-- blank [7]"
+                "   jmp entry"
+            );
+            common.load_program(&dshot_pio_program.program)
+        }
+        DshotMode::Bidirectional => {
+            // Define program, 10 cycles per transmitted bit, 8 per received bit
+            let dshot_pio_program = pio_proc::pio_asm!(
+                "entry:"
+                "   set pins 1" // Line idles high
+                "   set pindirs 1"
+                "   pull"
+                "   set x 15"
+                "tx_loop:"
+                "   set pins 0"
+                "   out y 1"
+                "   jmp !y tx_zero [1]"
+                "tx_one:" // 7 and 3
+                "   nop [2]"
+                "   set pins 1"
+                "   jmp x-- tx_loop [1]"
+                "   jmp rx"
+                "tx_zero:" // 4 and 6
+                "   set pins 1 [4]"
+                "   jmp x-- tx_loop"
+                "rx:" // Lower half-word is the reply timeout
+                "   out x 16"
+                "   set pindirs 0"
+                "wait_start:"
+                "   jmp pin still_high"
+                "   jmp rx_start"
+                "still_high:"
+                "   jmp x-- wait_start"
+                "   jmp entry" // No reply from ESC
+                "rx_start:" // Sample 21 bits at 5/4 bitrate
+                "   set x 20"
+                "rx_loop:"
+                "   in pins 1 [6]"
+                "   jmp x-- rx_loop"
+                "   push noblock" // Dropped if the RX FIFO is full, rather than stalling the frames
+            );
+            common.load_program(&dshot_pio_program.program)
+        }
+    }
+}
 
-fn configure_pio_instance<'a,PIO: Instance>  (
-    pio: impl Peripheral<P = PIO> + 'a,
-    irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
+/// Configuration a state machine runs the DShot program with, before its pin is set
+fn program_config<'a, PIO: Instance>(
+    program: &LoadedProgram<'a, PIO>,
     clk_div: (u16, u8),
-) -> (Config<'a, PIO>, Pio<'a, PIO>) {
-    
-    // Define program
-    let dshot_pio_program = pio_proc::pio_asm!(
-        "set pindirs, 1",
-        "entry:"
-        "   pull"
-        "   out null 16"
-        "   set x 15"
-        "loop:"
-        "   set pins 1"
-        "   out y 1"
-        "   jmp !y zero"
-        "   nop [2]"
-        "one:" // 6 and 2
-        "   set pins 0"
-        "   jmp x-- loop"
-        "   jmp reset"
-        "zero:" // 3 and 5
-        "   set pins 0 [3]"
-        "   jmp x-- loop"
-        "   jmp reset"
-        "reset:" // Blank frame
-        "   nop [31]"
-        "   nop [31]"
-        "   nop [31]"
-        "   jmp entry [31]"
-    );
-
-    // Configure program
+    mode: DshotMode,
+) -> Config<'a, PIO> {
     let mut cfg = Config::default();
-    let mut pio = Pio::new(pio,irq);
-    cfg.use_program(&pio.common.load_program(&dshot_pio_program.program), &[]);
-    cfg.clock_divider = clk_div.0.into();
-
-    cfg.shift_in = ShiftConfig {
-        auto_fill: true,
-        direction: Default::default(),
-        threshold: 32,
-    };
+    cfg.use_program(program, &[]);
+    cfg.clock_divider = clock_divider(clk_div);
+
+    match mode {
+        DshotMode::Normal => {
+            cfg.shift_in = ShiftConfig {
+                auto_fill: true,
+                direction: Default::default(),
+                threshold: 32,
+            };
+        }
+        DshotMode::Repeat { .. } => {}
+        DshotMode::Bidirectional => {
+            cfg.shift_in = ShiftConfig {
+                auto_fill: false,
+                direction: Left,
+                threshold: 32,
+            };
+        }
+    }
 
     cfg.shift_out = ShiftConfig {
         auto_fill: Default::default(),
@@ -62,250 +271,819 @@ fn configure_pio_instance<'a,PIO: Instance>  (
         threshold: Default::default(),
     };
 
-    (cfg,pio)
+    cfg
+}
+
+/// Clock divider in the fixed point format used by embassy-rp
+fn clock_divider(clk_div: (u16, u8)) -> FixedU32<U8> {
+    FixedU32::from_bits(((clk_div.0 as u32) << 8) | clk_div.1 as u32)
+}
+
+fn setup_state_machine<'a, PIO: Instance, const SM: usize>(
+    sm: &mut StateMachine<'a, PIO, SM>,
+    cfg: &mut Config<'a, PIO>,
+    pin: &mut Pin<'a, PIO>,
+    gpio: u8,
+    mode: DshotMode,
+) {
+    cfg.set_set_pins(&[&*pin]);
+
+    // The ESC replies on the same pin, which must idle high
+    if mode == DshotMode::Bidirectional {
+        pin.set_pull(Pull::Up);
+        cfg.set_in_pins(&[&*pin]);
+        cfg.set_jmp_pin(pin);
+    }
+
+    sm.set_config(cfg);
+    point_at_pin::<PIO, SM>(gpio);
+}
+
+/// Stop a state machine, drop any frames not yet sent, and reapply its
+/// configuration, which also moves it back to the start of the program
+fn reset_state_machine<'a, PIO: Instance, const SM: usize>(
+    sm: &mut StateMachine<'a, PIO, SM>,
+    cfg: &Config<'a, PIO>,
+    gpio: u8,
+) {
+    sm.set_enable(false);
+    sm.clear_fifos();
+    sm.restart();
+    // The repeating program would otherwise resend the last frame left in X
+    let instr = InstructionOperands::MOV {
+        destination: MovDestination::X,
+        op: MovOperation::None,
+        source: MovSource::NULL,
+    }.encode();
+    // SAFETY: The state machine is stopped, and clearing X only changes the frame it sends next
+    unsafe { sm.exec_instr(instr) };
+    sm.set_config(cfg);
+    point_at_pin::<PIO, SM>(gpio);
+}
+
+//
+// Direct access to the PIO block registers
+//
+
+/// Index of PIO block `PIO`, told apart by its interrupt
+fn pio_index<PIO: Instance>() -> usize {
+    match <PIO::Interrupt as interrupt::typelevel::Interrupt>::IRQ {
+        interrupt::Interrupt::PIO0_IRQ_0 => 0,
+        interrupt::Interrupt::PIO1_IRQ_0 => 1,
+        _ => 2,
+    }
+}
+
+/// Registers of PIO block `PIO`, for the accesses embassy-rp has no API for
+fn pio_block<PIO: Instance>() -> pac::pio::Pio {
+    match pio_index::<PIO>() {
+        0 => pac::PIO0,
+        #[cfg(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb"))]
+        2 => pac::PIO2,
+        _ => pac::PIO1,
+    }
+}
+
+/// Select which 32 GPIOs PIO block `PIO` can access, returning the offset to subtract
+/// from the GPIO numbers. The RP2350B has 48 GPIOs, of which a PIO block either
+/// sees 0 to 31, or 16 to 47, while the other chips always use 0 to 31.
+#[cfg_attr(
+    not(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb")),
+    allow(clippy::extra_unused_type_parameters)
+)]
+fn set_gpio_base<PIO: Instance>(pins: &[u8]) -> u8 {
+    #[cfg(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb"))]
+    {
+        let base = match pins.iter().any(|&pin| pin >= 32) {
+            true => 16,
+            false => 0,
+        };
+        assert!(
+            pins.iter().all(|&pin| pin >= base),
+            "Pins of a PIO block must all be within GPIO 0-31 or GPIO 16-47"
+        );
+        pio_block::<PIO>().gpiobase().write_value(pac::pio::regs::Gpiobase(base as u32));
+        base
+    }
+
+    #[cfg(not(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb")))]
+    {
+        let _ = pins;
+        0
+    }
+}
+
+/// GPIO base PIO block `PIO` already has, checking that the pins are within reach of it.
+/// Moving the base would also move the pins of any other programs on the block.
+#[cfg_attr(
+    not(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb")),
+    allow(clippy::extra_unused_type_parameters)
+)]
+fn gpio_base<PIO: Instance>(pins: &[u8]) -> u8 {
+    #[cfg(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb"))]
+    {
+        let base = pio_block::<PIO>().gpiobase().read().0 as u8;
+        assert!(
+            pins.iter().all(|&pin| pin >= base && pin < base + 32),
+            "Pins of a shared PIO block must be within reach of its GPIO base"
+        );
+        base
+    }
+
+    #[cfg(not(any(feature = "embassy-rp235xa", feature = "embassy-rp235xb")))]
+    {
+        let _ = pins;
+        0
+    }
+}
+
+/// GPIO numbers of the pins, relative to the GPIO base of the PIO block.
+/// A driver sharing the block keeps the GPIO base it already has.
+fn gpio_numbers<PIO: Instance, const N: usize>(pins: &[Pin<'_, PIO>; N], shared: bool) -> [u8; N] {
+    let gpio = pins.each_ref().map(|pin| pin.pin());
+    let base = match shared {
+        true => gpio_base::<PIO>(&gpio),
+        false => set_gpio_base::<PIO>(&gpio),
+    };
+    gpio.map(|pin| pin - base)
+}
+
+/// Bits of the PIO CTRL register
+const CTRL_SM_ENABLE: u32 = 0;
+const CTRL_SM_RESTART: u32 = 4;
+const CTRL_CLKDIV_RESTART: u32 = 8;
+
+/// Set bits of the CTRL register of PIO block `PIO`, through its atomic alias
+fn ctrl_set<PIO: Instance>(bits: u32) {
+    let ctrl = pio_block::<PIO>().ctrl().as_ptr();
+
+    // SAFETY: Writes to the atomic alias only affect the given bits, which
+    // belong to state machines owned by the driver
+    unsafe { ((ctrl as usize + 0x2000) as *mut u32).write_volatile(bits) }
+}
+
+/// Point the SET, IN and JMP pins of state machine `SM` at `gpio`, relative to the GPIO
+/// base. Needed after every `set_config`, as embassy-rp takes the GPIO numbers as they are.
+fn point_at_pin<PIO: Instance, const SM: usize>(gpio: u8) {
+    let sm = pio_block::<PIO>().sm(SM);
+    sm.pinctrl().modify(|w| {
+        w.set_set_base(gpio);
+        w.set_in_base(gpio);
+    });
+    sm.execctrl().modify(|w| w.set_jmp_pin(gpio));
+}
+
+/// Run `set <destination>, <data>` on each of the given GPIOs of stopped state machine `SM`,
+/// one at a time, with the GPIOs relative to the GPIO base. Its SET pins are kept as they were.
+fn set_each_pin<PIO: Instance, const SM: usize>(
+    sm: &mut StateMachine<'_, PIO, SM>,
+    gpio: &[u8],
+    destination: SetDestination,
+    data: u8,
+) {
+    let pinctrl = pio_block::<PIO>().sm(SM).pinctrl();
+    let saved = pinctrl.read();
+    let instr = InstructionOperands::SET { destination, data }.encode();
 
+    for &pin in gpio {
+        pinctrl.write(|w| {
+            w.set_set_base(pin);
+            w.set_set_count(1);
+        });
+        // SAFETY: The state machine is stopped, and the instruction only drives the given pin
+        unsafe { sm.exec_instr(instr) };
+    }
+    pinctrl.write_value(saved);
 }
 
-///
-/// Defining constructor functions
-/// 
+//
+// Defining constructor functions
+//
 
-impl <'a,PIO: Instance> DshotPio<'a,1,PIO> {
+impl <'a, const N : usize, PIO: Instance> DshotPio<'a,N,PIO> {
     pub fn new(
         pio: impl Peripheral<P = PIO> + 'a,
         irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
-        pin0: impl PioPin,
+        pins: impl DshotPins<'a, PIO, N>,
         clk_div: (u16, u8),
-    ) -> DshotPio<'a,1,PIO> {
+    ) -> DshotPio<'a,N,PIO> {
+        Self::new_with_mode(pio, irq, pins, clk_div, DshotMode::Normal)
+    }
 
-        let (mut cfg, mut pio) = configure_pio_instance(pio, irq, clk_div);
+    /// Bidirectional DShot, where eRPM replies are read back through `erpm`.
+    /// Uses 10 PIO cycles per bit rather than 8 when computing the clock divider.
+    pub fn new_bidirectional(
+        pio: impl Peripheral<P = PIO> + 'a,
+        irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
+        pins: impl DshotPins<'a, PIO, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<'a,N,PIO> {
+        Self::new_with_mode(pio, irq, pins, clk_div, DshotMode::Bidirectional)
+    }
 
-        // Set pins and enable all state machines
-        let pin0 = pio.common.make_pio_pin(pin0);
-        cfg.set_set_pins(&[&pin0]);
-        pio.sm0.set_config(&cfg);
-        pio.sm0.set_enable(true);
+    /// Like `new`, but computing the clock divider for the given DShot speed from the
+    /// system clock frequency in Hz, as given by `embassy_rp::clocks::clk_sys_freq()`
+    pub fn with_speed(
+        pio: impl Peripheral<P = PIO> + 'a,
+        irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
+        pins: impl DshotPins<'a, PIO, N>,
+        speed: DshotSpeed,
+        sys_clk_hz: u32,
+    ) -> Result<DshotPio<'a,N,PIO>, DshotError> {
+        let clk_div = speed.clock_divider(sys_clk_hz, DshotMode::Normal)?;
+        Ok(Self::new_with_mode(pio, irq, pins, clk_div, DshotMode::Normal))
+    }
 
-        // Return struct of 1 configured DShot state machine
-        DshotPio { pio_instance : pio }
+    /// Like `new_bidirectional`, but computing the clock divider for the given DShot speed
+    /// from the system clock frequency in Hz, as given by `embassy_rp::clocks::clk_sys_freq()`
+    pub fn with_speed_bidirectional(
+        pio: impl Peripheral<P = PIO> + 'a,
+        irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
+        pins: impl DshotPins<'a, PIO, N>,
+        speed: DshotSpeed,
+        sys_clk_hz: u32,
+    ) -> Result<DshotPio<'a,N,PIO>, DshotError> {
+        let clk_div = speed.clock_divider(sys_clk_hz, DshotMode::Bidirectional)?;
+        Ok(Self::new_with_mode(pio, irq, pins, clk_div, DshotMode::Bidirectional))
     }
-}
 
-impl <'a,PIO: Instance> DshotPio<'a,2,PIO> {
-    pub fn new(
+    /// Like `new`, with the given signalling mode. Used for `DshotMode::Repeat`, where the state
+    /// machines keep resending the last frame, so that the ESCs see a steady frame rate however
+    /// irregularly the CPU pushes frames.
+    pub fn new_with_mode(
         pio: impl Peripheral<P = PIO> + 'a,
         irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
-        pin0: impl PioPin,
-        pin1: impl PioPin,
+        pins: impl DshotPins<'a, PIO, N>,
         clk_div: (u16, u8),
-    ) -> DshotPio<'a,2,PIO> {
+        mode: DshotMode,
+    ) -> DshotPio<'a,N,PIO> {
+        let () = Self::VALID_N;
 
-        let (mut cfg, mut pio) = configure_pio_instance(pio, irq, clk_div);
+        let Pio { mut common, sm0, sm1, sm2, sm3, .. } = Pio::new(pio, irq);
+        let mut dshot = DshotPio::empty(&mut common, pins, clk_div, mode, false);
+        (dshot.sm0, dshot.sm1, dshot.sm2, dshot.sm3) = (Some(sm0), Some(sm1), Some(sm2), Some(sm3));
+        dshot.state_machines = core::array::from_fn(|sm| sm);
 
-        // Set pins and enable all state machines
-        let pin0 = pio.common.make_pio_pin(pin0);
-        cfg.set_set_pins(&[&pin0]);
-        pio.sm0.set_config(&cfg);
-        pio.sm0.set_enable(true);
+        dshot.setup_state_machines();
+        dshot.common = Some(common);
+        dshot.start_in_phase();
+        dshot
+    }
 
-        let pin1 = pio.common.make_pio_pin(pin1);
-        cfg.set_set_pins(&[&pin1]);
-        pio.sm1.set_config(&cfg);
-        pio.sm1.set_enable(true);
+    /// Like `new`, but sharing the PIO block with other programs. Only the given state machines
+    /// are used, and the DShot program takes up only part of the instruction memory, so the
+    /// rest of the block remains available through `common` and the other state machines.
+    pub fn from_split<S: DshotStateMachines<'a, PIO, N>>(
+        common: &mut Common<'a, PIO>,
+        state_machines: S,
+        pins: impl DshotPins<'a, PIO, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<'a,N,PIO,S> {
+        Self::from_split_with_mode(common, state_machines, pins, clk_div, DshotMode::Normal)
+    }
 
-        // Return struct of 2 configured DShot state machines
-        DshotPio { pio_instance : pio }
+    /// Like `new_bidirectional`, but sharing the PIO block with other programs, see `from_split`
+    pub fn from_split_bidirectional<S: DshotStateMachines<'a, PIO, N>>(
+        common: &mut Common<'a, PIO>,
+        state_machines: S,
+        pins: impl DshotPins<'a, PIO, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<'a,N,PIO,S> {
+        Self::from_split_with_mode(common, state_machines, pins, clk_div, DshotMode::Bidirectional)
     }
-}
 
-impl <'a,PIO: Instance> DshotPio<'a,3,PIO> {
-    pub fn new(
-        pio: impl Peripheral<P = PIO> + 'a,
-        irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
-        pin0: impl PioPin,
-        pin1: impl PioPin,
-        pin2: impl PioPin,
+    /// Like `new_with_mode`, but sharing the PIO block with other programs, see `from_split`
+    pub fn from_split_with_mode<S: DshotStateMachines<'a, PIO, N>>(
+        common: &mut Common<'a, PIO>,
+        state_machines: S,
+        pins: impl DshotPins<'a, PIO, N>,
         clk_div: (u16, u8),
-    ) -> DshotPio<'a,3,PIO> {
+        mode: DshotMode,
+    ) -> DshotPio<'a,N,PIO,S> {
+        let () = Self::VALID_N;
+
+        let mut dshot = DshotPio::empty(common, pins, clk_div, mode, true);
+        sealed::HandOver::hand_over(state_machines, &mut dshot);
+
+        dshot.setup_state_machines();
+        dshot.start_in_phase();
+        dshot
+    }
+
+    /// Stop the state machines, drive the pins low and free the instruction memory of the program,
+    /// giving back the parts of the PIO block and the pins. Drivers sharing their PIO block are
+    /// given back with `release`.
+    pub fn free(mut self) -> (DshotPioParts<'a, PIO>, [Pin<'a, PIO>; N]) {
+        let mut common = self.common.take().expect("PIO block owned by the driver");
+        self.halt();
+        self.drive_low();
 
-        let (mut cfg, mut pio) = configure_pio_instance(pio, irq, clk_div);
+        // SAFETY: No state machine runs the program anymore
+        unsafe { common.free_instr(self.program.used_memory) };
 
-        // Set pins and enable all state machines
-        let pin0 = pio.common.make_pio_pin(pin0);
-        cfg.set_set_pins(&[&pin0]);
-        pio.sm0.set_config(&cfg);
-        pio.sm0.set_enable(true);
+        let sm0 = self.sm0.take().expect("PIO block owned by the driver");
+        let sm1 = self.sm1.take().expect("PIO block owned by the driver");
+        let sm2 = self.sm2.take().expect("PIO block owned by the driver");
+        let sm3 = self.sm3.take().expect("PIO block owned by the driver");
+        ((common, sm0, sm1, sm2, sm3), self.pins)
+    }
+}
 
-        let pin1 = pio.common.make_pio_pin(pin1);
-        cfg.set_set_pins(&[&pin1]);
-        pio.sm1.set_config(&cfg);
-        pio.sm1.set_enable(true);
+impl <'a, const N : usize, PIO: Instance, S: DshotStateMachines<'a, PIO, N>> DshotPio<'a,N,PIO,S> {
+    /// Like `free`, but for drivers sharing their PIO block, see `from_split`. Gives back
+    /// the state machines, as the same tuple they were given as, and the pins.
+    pub fn release(mut self, common: &mut Common<'a, PIO>) -> (S, [Pin<'a, PIO>; N]) {
+        self.halt();
+        self.drive_low();
 
-        let pin2 = pio.common.make_pio_pin(pin2);
-        cfg.set_set_pins(&[&pin2]);
-        pio.sm2.set_config(&cfg);
-        pio.sm2.set_enable(true);
-        
-        // Return struct of 3 configured DShot state machines
-        DshotPio { pio_instance : pio }
+        let state_machines = sealed::HandOver::take_back(&mut self);
+
+        // SAFETY: No state machine runs the program anymore
+        unsafe { common.free_instr(self.program.used_memory) };
+
+        (state_machines, self.pins)
     }
 }
 
-impl <'a,PIO: Instance> DshotPio<'a,4,PIO> {
-    pub fn new(
-        pio: impl Peripheral<P = PIO> + 'a,
-        irq: impl Binding<PIO::Interrupt, InterruptHandler<PIO>>,
-        pin0: impl PioPin,
-        pin1: impl PioPin,
-        pin2: impl PioPin,
-        pin3: impl PioPin,
-        clk_div: (u16, u8),
-    ) -> DshotPio<'a,4,PIO> {
+impl <'a, const N : usize, PIO: Instance, S> DshotPio<'a,N,PIO,S> {
+    /// A PIO block only has four state machines to drive DShot with
+    const VALID_N: () = assert!(N >= 1 && N <= 4, "DshotPio supports 1 to 4 motors per PIO block");
+
+    /// Driver without any state machines yet, with the DShot program loaded and the pins set up
+    fn empty(common: &mut Common<'a, PIO>, pins: impl DshotPins<'a, PIO, N>, clk_div: (u16, u8), mode: DshotMode, shared: bool) -> DshotPio<'a,N,PIO,S> {
+        let program = load_program(common, mode);
+        let pins = pins.make_pio_pins(common);
+        DshotPio {
+            common: None,
+            sm0: None,
+            sm1: None,
+            sm2: None,
+            sm3: None,
+            state_machines: [0; N],
+            configs: core::array::from_fn(|_| program_config(&program, clk_div, mode)),
+            gpio: gpio_numbers(&pins, shared),
+            pins,
+            program,
+            mode,
+            given: PhantomData,
+        }
+    }
+
+    /// Configure the state machine of each pin
+    fn setup_state_machines(&mut self) {
+        let mode = self.mode;
+        let motors = self.state_machines.into_iter().zip(&mut self.configs).zip(&mut self.pins).zip(self.gpio);
+        for (((index, cfg), pin), gpio) in motors {
+            with_sm!(self, index, |sm| setup_state_machine(sm, cfg, pin, gpio, mode));
+        }
+    }
 
-        let (mut cfg, mut pio) = configure_pio_instance(pio, irq, clk_div);
+    /// Drive the pins of the stopped state machines low
+    fn drive_low(&mut self) {
+        for motor in 0..N {
+            let gpio = [self.gpio[motor]];
+            with_sm!(self, self.state_machines[motor], |sm| {
+                set_each_pin(sm, &gpio, SetDestination::PINS, 0);
+                set_each_pin(sm, &gpio, SetDestination::PINDIRS, 1);
+            });
+        }
+    }
 
-        // Set pins and enable all state machines
-        let pin0 = pio.common.make_pio_pin(pin0);
-        cfg.set_set_pins(&[&pin0]);
-        pio.sm0.set_config(&cfg);
-        pio.sm0.set_enable(true);
+    /// Restart the state machines so that all motors' frames start on the same cycle again.
+    /// Any frames not yet sent are dropped.
+    pub fn resync(&mut self) {
+        self.start_in_phase();
+    }
 
-        let pin1 = pio.common.make_pio_pin(pin1);
-        cfg.set_set_pins(&[&pin1]);
-        pio.sm1.set_config(&cfg);
-        pio.sm1.set_enable(true);
+    /// Change the DShot speed at runtime, given the system clock frequency in Hz,
+    /// as given by `embassy_rp::clocks::clk_sys_freq()`. See `set_clock_divider`.
+    pub fn set_speed(&mut self, speed: DshotSpeed, sys_clk_hz: u32) -> Result<(), DshotError> {
+        self.set_clock_divider(speed.clock_divider(sys_clk_hz, self.mode)?);
+        Ok(())
+    }
 
-        let pin2 = pio.common.make_pio_pin(pin2);
-        cfg.set_set_pins(&[&pin2]);
-        pio.sm2.set_config(&cfg);
-        pio.sm2.set_enable(true);
+    /// Change the clock divider at runtime. The state machines are stopped, any frames
+    /// not yet sent are dropped, and they are then restarted in phase at the new speed.
+    pub fn set_clock_divider(&mut self, clk_div: (u16, u8)) {
+        for cfg in self.configs.iter_mut() {
+            cfg.clock_divider = clock_divider(clk_div);
+        }
+        self.start_in_phase();
+    }
 
-        let pin3 = pio.common.make_pio_pin(pin3);
-        cfg.set_set_pins(&[&pin3]);
-        pio.sm3.set_config(&cfg);
-        pio.sm3.set_enable(true);
+    /// Reset the state machines in use, then enable them and
+    /// restart their clock dividers on the same cycle
+    fn start_in_phase(&mut self) {
+        self.halt();
+        self.start();
+    }
+}
 
-        // Return struct of 4 configured DShot state machines
-        DshotPio { pio_instance : pio }
+impl <'d, const N : usize, PIO : Instance, S> super::DshotSync for DshotPio<'d,N,PIO,S> {
+    fn halt(&mut self) {
+        for (motor, cfg) in self.configs.iter().enumerate() {
+            with_sm!(self, self.state_machines[motor], |sm| reset_state_machine(sm, cfg, self.gpio[motor]));
+        }
+    }
+
+    fn start(&mut self) {
+        // A single write, so that the state machines start on the same cycle,
+        // whether or not the driver owns the common handle
+        let mask = self.state_machines.iter().fold(0, |mask, &sm| mask | 1 << sm);
+        ctrl_set::<PIO>(mask << CTRL_SM_ENABLE | mask << CTRL_SM_RESTART | mask << CTRL_CLKDIV_RESTART);
+    }
+}
+
+impl <'d, const N : usize, PIO : Instance, S> super::DshotLocate for DshotPio<'d,N,PIO,S> {
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        (pio_index::<PIO>(), self.state_machines[motor])
     }
 }
 
-///
-/// Implementing DshotPioTrait
-/// 
+//
+// DMA frame upload
+//
 
-impl <'d,PIO : Instance> super::DshotPioTrait<1> for DshotPio<'d,1,PIO> {
-    
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 1]) {
-        self.pio_instance.sm0.tx().push(command[0].min(dshot::THROTTLE_MAX) as u32);
+impl <'d, const N : usize, PIO : Instance, S> DshotPio<'d,N,PIO,S> {
+    /// Encode a special command or throttle value into the word expected by the state machines,
+    /// for filling buffers to upload with `dma_push`
+    pub fn encode(&self, value: DshotValue, telemetry: bool) -> u32 {
+        self.mode.tx_word(value.frame(telemetry))
     }
-    
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool;1]) {
-        self.pio_instance.sm0.tx().push(dshot::reverse(reverse[0]) as u32);
+
+    /// Upload a buffer of encoded words to each motor by DMA, one channel per motor. The
+    /// transfers are paced by the TX FIFOs, so each word is sent as its own DShot frame.
+    /// Completes once every word has been pushed, and stops the transfers if dropped early.
+    pub async fn dma_push(&mut self, channels: [PeripheralRef<'_, AnyChannel>; N], words: [&[u32]; N]) {
+        let state_machines = self.state_machines;
+        let (mut sm0, mut sm1, mut sm2, mut sm3) = (self.sm0.as_mut(), self.sm1.as_mut(), self.sm2.as_mut(), self.sm3.as_mut());
+
+        // All transfers start right away, and run alongside each other
+        let mut channels = channels.into_iter();
+        let transfers: [Transfer<'_, AnyChannel>; N] = core::array::from_fn(|motor| {
+            let channel = channels.next().expect("One DMA channel per motor");
+            match state_machines[motor] {
+                0 => sm0.take().unwrap().tx().dma_push(channel, words[motor]),
+                1 => sm1.take().unwrap().tx().dma_push(channel, words[motor]),
+                2 => sm2.take().unwrap().tx().dma_push(channel, words[motor]),
+                _ => sm3.take().unwrap().tx().dma_push(channel, words[motor]),
+            }
+        });
+
+        for transfer in transfers {
+            transfer.await;
+        }
     }
+}
 
-    /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;1]) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_clamp(throttle[0], false) as u32);
+//
+// Non-blocking and async sending
+//
+
+impl <'d, const N : usize, PIO : Instance, S> DshotPio<'d,N,PIO,S> {
+    /// Send a special command or throttle value to each motor without blocking.
+    /// Returns which motors had their frame dropped because their TX FIFO was full.
+    pub fn try_command(&mut self, command: [DshotValue; N], telemetry: [bool;N]) -> [bool;N] {
+        let words: [u32;N] = core::array::from_fn(|i| self.mode.tx_word(command[i].frame(telemetry[i])));
+        self.try_dispatch(words)
     }
 
-    /// Set the throttle for each motor to zero (DShot command 48)
-    fn throttle_minimum(&mut self) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_minimum(false) as u32);
+    /// Set the throttle for each motor without blocking, clamped between 48 and 2047.
+    /// Returns which motors had their frame dropped because their TX FIFO was full.
+    pub fn try_throttle_clamp(&mut self, throttle: [u16;N]) -> [bool;N] {
+        let words = throttle.map(|throttle| self.mode.tx_word(dshot::throttle_clamp(throttle, false)));
+        self.try_dispatch(words)
+    }
+
+    /// Set the throttle for each motor to zero without blocking (DShot command 48).
+    /// Returns which motors had their frame dropped because their TX FIFO was full.
+    pub fn try_throttle_minimum(&mut self) -> [bool;N] {
+        let word = self.mode.tx_word(dshot::throttle_minimum(false));
+        self.try_dispatch([word;N])
+    }
+
+    /// Push word `i` into the TX FIFO of state machine `i` without blocking,
+    /// returning which words were dropped because their FIFO was full
+    fn try_dispatch(&mut self, words: [u32;N]) -> [bool;N] {
+        let mut dropped = [false;N];
+        for (motor, word) in words.into_iter().enumerate() {
+            dropped[motor] = !with_sm!(self, self.state_machines[motor], |sm| sm.tx().try_push(word));
+        }
+        dropped
+    }
+
+    /// Push a word into the TX FIFO of the state machine of `motor`, waiting for room if it is full
+    async fn push_async(&mut self, motor: usize, word: u32) {
+        with_sm!(self, self.state_machines[motor], |sm| sm.tx().wait_push(word).await)
+    }
+
+    /// Push word `i` into the TX FIFO of state machine `i`, waiting for room in each
+    async fn dispatch_async(&mut self, words: [u32;N]) {
+        for (sm, word) in words.into_iter().enumerate() {
+            self.push_async(sm, word).await;
+        }
+    }
+}
+
+impl <'d, const N : usize, PIO : Instance, S> super::DshotPioTry<N> for DshotPio<'d,N,PIO,S> {
+    fn try_command(&mut self, command: [DshotValue; N], telemetry: [bool;N]) -> [bool;N] {
+        DshotPio::try_command(self, command, telemetry)
+    }
+}
+
+//
+// Implementing DshotPioTrait
+//
+
+impl <'d, const N : usize, PIO : Instance, S> fifo::DshotFifo for DshotPio<'d,N,PIO,S> {
+    fn mode(&self) -> DshotMode {
+        self.mode
+    }
+
+    fn is_full(&mut self, motor: usize) -> bool {
+        with_sm!(self, self.state_machines[motor], |sm| sm.tx().full())
+    }
+
+    fn push(&mut self, motor: usize, word: u32) {
+        with_sm!(self, self.state_machines[motor], |sm| sm.tx().push(word))
+    }
+
+    fn pull(&mut self, motor: usize) -> Option<u32> {
+        with_sm!(self, self.state_machines[motor], |sm| sm.rx().try_pull())
     }
 }
 
-impl <'d,PIO : Instance> super::DshotPioTrait<2> for DshotPio<'d,2,PIO> {
+impl <'d, const N : usize, PIO : Instance, S> super::DshotPioTrait<N> for DshotPio<'d,N,PIO,S> {
     
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 2]) {
-        self.pio_instance.sm0.tx().push(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[1].min(dshot::THROTTLE_MAX) as u32);
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool;N]) {
+        fifo::command(self, command, telemetry);
+    }
+
+    /// Send a special command to each motor, motors given `None` are left alone
+    fn send_command(&mut self, command: [Option<DshotCommand>;N]) {
+        fifo::send_command(self, command);
     }
     
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool;2]) {
-        self.pio_instance.sm0.tx().push(dshot::reverse(reverse[0]) as u32);
-        self.pio_instance.sm1.tx().push(dshot::reverse(reverse[1]) as u32);
+    /// Set the direction of rotation for each motor (DShot command 20 or 21),
+    /// sending the command as many times as the ESCs require
+    fn reverse(&mut self, reverse: [bool;N]) {
+        fifo::reverse(self, reverse);
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    fn extended_telemetry(&mut self, enable: [bool;N]) {
+        fifo::extended_telemetry(self, enable);
     }
 
     /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;2]) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_clamp(throttle[1], false) as u32);
+    fn throttle_clamp(&mut self, throttle: [u16;N]) {
+        fifo::throttle_clamp(self, throttle, [false;N]);
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16;N], telemetry: [bool;N]) {
+        fifo::throttle_clamp(self, throttle, telemetry);
     }
 
     /// Set the throttle for each motor to zero (DShot command 48)
     fn throttle_minimum(&mut self) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_minimum(false) as u32);
+        fifo::throttle_minimum::<N>(self);
+    }
+
+    /// Get the latest eRPM reported by each motor, if in bidirectional mode. The RX FIFO
+    /// holds 4 replies, and newer replies are dropped while it is full, so this should be
+    /// called every frame for the value to be the latest one.
+    fn erpm(&mut self) -> [Option<u32>;N] {
+        core::array::from_fn(|motor| fifo::latest_erpm(self, motor))
+    }
+
+    /// Get the oldest unread reply from each motor, if in bidirectional mode. Unlike
+    /// `erpm`, this does not discard older replies, and also yields EDT frames.
+    fn telemetry(&mut self) -> [Option<Telemetry>;N] {
+        core::array::from_fn(|motor| fifo::next_telemetry(self, motor))
     }
 }
 
-impl <'d,PIO : Instance> super::DshotPioTrait<3> for DshotPio<'d,3,PIO> {
-    
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 3]) {
-        self.pio_instance.sm0.tx().push(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[1].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[2].min(dshot::THROTTLE_MAX) as u32);
+impl <'d, const N : usize, PIO : Instance, S> super::DshotPioAsync<N> for DshotPio<'d,N,PIO,S> {
+
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry
+    async fn command_async(&mut self, command: [DshotValue; N], telemetry: [bool;N]) {
+        let words: [u32;N] = core::array::from_fn(|i| self.mode.tx_word(command[i].frame(telemetry[i])));
+        self.dispatch_async(words).await;
     }
-    
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool;3]) {
-        self.pio_instance.sm0.tx().push(dshot::reverse(reverse[0]) as u32);
-        self.pio_instance.sm1.tx().push(dshot::reverse(reverse[1]) as u32);
-        self.pio_instance.sm2.tx().push(dshot::reverse(reverse[2]) as u32);
+
+    /// Send a special command to each motor, motors given `None` are left alone
+    async fn send_command_async(&mut self, command: [Option<DshotCommand>;N]) {
+        for (sm, command) in command.into_iter().enumerate() {
+            if let Some(command) = command {
+                self.push_async(sm, self.mode.tx_word(command.frame())).await;
+            }
+        }
+    }
+
+    /// Set the direction of rotation for each motor (DShot command 20 or 21),
+    /// sending the command as many times as the ESCs require
+    async fn reverse_async(&mut self, reverse: [bool;N]) {
+        let words = reverse.map(|reverse| self.mode.tx_word(dshot::reverse(reverse)));
+        for _ in 0..DshotCommand::SpinDirectionNormal.repeats() {
+            self.dispatch_async(words).await;
+        }
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    async fn extended_telemetry_async(&mut self, enable: [bool;N]) {
+        let words = enable.map(|enable| self.mode.tx_word(super::extended_telemetry(enable)));
+        for _ in 0..DshotCommand::ExtendedTelemetryEnable.repeats() {
+            self.dispatch_async(words).await;
+        }
     }
 
     /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;3]) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_clamp(throttle[1], false) as u32);
-        self.pio_instance.sm2.tx().push(dshot::throttle_clamp(throttle[2], false) as u32);
+    async fn throttle_clamp_async(&mut self, throttle: [u16;N]) {
+        let words = throttle.map(|throttle| self.mode.tx_word(dshot::throttle_clamp(throttle, false)));
+        self.dispatch_async(words).await;
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors
+    async fn throttle_clamp_telemetry_async(&mut self, throttle: [u16;N], telemetry: [bool;N]) {
+        let words: [u32;N] = core::array::from_fn(|i| self.mode.tx_word(dshot::throttle_clamp(throttle[i], telemetry[i])));
+        self.dispatch_async(words).await;
     }
 
     /// Set the throttle for each motor to zero (DShot command 48)
-    fn throttle_minimum(&mut self) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm2.tx().push(dshot::throttle_minimum(false) as u32);
+    async fn throttle_minimum_async(&mut self) {
+        let word = self.mode.tx_word(dshot::throttle_minimum(false));
+        self.dispatch_async([word;N]).await;
     }
 }
 
-impl <'d,PIO : Instance> super::DshotPioTrait<4 > for DshotPio<'d,4,PIO> {
-    
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 4]) {
-        self.pio_instance.sm0.tx().push(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[1].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[2].min(dshot::THROTTLE_MAX) as u32);
-        self.pio_instance.sm0.tx().push(command[3].min(dshot::THROTTLE_MAX) as u32);
+//
+// Parallel output from a single state machine
+//
+
+/// Drives N (1 to 8) motors on consecutive pins from a single state machine, leaving the
+/// other state machines of the PIO block free. All motors' edges are perfectly in sync.
+/// Only supports regular DShot, and uses 16 PIO cycles per bit when computing the clock divider.
+pub struct DshotPioParallel<'a, const N : usize, PIO : Instance, const SM : usize> {
+    sm: StateMachine<'a, PIO, SM>,
+    pins: [Pin<'a, PIO>; N],
+    /// GPIO number of each pin, relative to the GPIO base of the PIO block
+    gpio: [u8; N],
+    program: LoadedProgram<'a, PIO>,
+}
+
+impl <'a, const N : usize, PIO: Instance, const SM : usize> DshotPioParallel<'a,N,PIO,SM> {
+    /// A state machine can drive at most eight pins in parallel
+    const VALID_N: () = assert!(N >= 1 && N <= parallel::MAX_MOTORS, "DshotPioParallel supports 1 to 8 motors");
+
+    /// Load the parallel program into the PIO block, and start it on the given state machine.
+    /// The pins must be consecutive, in increasing order.
+    pub fn new(
+        common: &mut Common<'a, PIO>,
+        mut sm: StateMachine<'a, PIO, SM>,
+        pins: impl DshotPins<'a, PIO, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPioParallel<'a,N,PIO,SM> {
+        let () = Self::VALID_N;
+
+        let pins = pins.make_pio_pins(common);
+        assert!(
+            pins.windows(2).all(|pair| pair[1].pin() == pair[0].pin() + 1),
+            "DshotPioParallel pins must be consecutive"
+        );
+
+        // Define program, 16 cycles per bit. Stalls only happen while the lines are low.
+        let dshot_pio_program = pio_proc::pio_asm!(
+            "entry:"
+            "   out isr 32" // Mask of motors receiving a frame
+            "   set x 15"
+            "loop:"
+            "   out y 8"
+            "   mov pins isr [5]"
+            "   mov pins y [5]" // 12 and 4 for ones, 6 and 10 for zeros
+            "   mov pins null [1]"
+            "   jmp x-- loop"
+            "reset:" // Blank frame
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   jmp entry [31]"
+        );
+
+        let program = common.load_program(&dshot_pio_program.program);
+        let mut cfg = Config::default();
+        cfg.use_program(&program, &[]);
+        cfg.clock_divider = clock_divider(clk_div);
+
+        cfg.set_out_pins(&pins.each_ref());
+        let gpio = gpio_numbers(&pins, true);
+
+        // The RX FIFO is unused, so join it to the TX FIFO to hold a frame and a half
+        cfg.fifo_join = FifoJoin::TxOnly;
+        cfg.shift_out = ShiftConfig {
+            auto_fill: true,
+            direction: Right,
+            threshold: 32,
+        };
+
+        sm.set_config(&cfg);
+        pio_block::<PIO>().sm(SM).pinctrl().modify(|w| w.set_out_base(gpio[0]));
+        set_each_pin(&mut sm, &gpio, SetDestination::PINDIRS, 1);
+        sm.set_enable(true);
+
+        DshotPioParallel { sm, pins, gpio, program }
     }
-    
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool;4]) {
-        self.pio_instance.sm0.tx().push(dshot::reverse(reverse[0]) as u32);
-        self.pio_instance.sm1.tx().push(dshot::reverse(reverse[1]) as u32);
-        self.pio_instance.sm2.tx().push(dshot::reverse(reverse[2]) as u32);
-        self.pio_instance.sm3.tx().push(dshot::reverse(reverse[3]) as u32);
+
+    /// Stop the state machine, drive the pins low and free the instruction memory of the
+    /// program, giving back the state machine and the pins. Any frames not yet sent are dropped.
+    pub fn release(mut self, common: &mut Common<'a, PIO>) -> (StateMachine<'a, PIO, SM>, [Pin<'a, PIO>; N]) {
+        self.sm.set_enable(false);
+        self.sm.clear_fifos();
+        set_each_pin(&mut self.sm, &self.gpio, SetDestination::PINS, 0);
+
+        // SAFETY: The state machine does not run the program anymore
+        unsafe { common.free_instr(self.program.used_memory) };
+
+        (self.sm, self.pins)
+    }
+
+    /// Send a frame to each motor given `Some`, leaving the others alone. This waits
+    /// for room in the FIFO, as a partially sent frame would garble the next ones.
+    fn push_frames(&mut self, frames: [Option<u16>;N]) {
+        let words = parallel::frame_words(frames);
+
+        // Write the whole frame at once, as the program would otherwise stall halfway
+        // through it while the CPU is interrupted, stretching one of the bits
+        while self.sm.tx().level() as usize > parallel::FIFO_DEPTH - parallel::FRAME_WORDS {}
+        critical_section::with(|_| {
+            for word in words {
+                self.sm.tx().push(word);
+            }
+        });
+    }
+}
+
+impl <'a, const N : usize, PIO : Instance, const SM : usize> super::DshotLocate for DshotPioParallel<'a,N,PIO,SM> {
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        assert!(motor < N, "Motor index out of range");
+        (pio_index::<PIO>(), SM)
+    }
+}
+
+impl <'a, const N : usize, PIO : Instance, const SM : usize> super::DshotPioTrait<N> for DshotPioParallel<'a,N,PIO,SM> {
+
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool;N]) {
+        self.push_frames(core::array::from_fn(|i| Some(command[i].frame(telemetry[i]))));
+    }
+
+    /// Send a special command to each motor, motors given `None` are left alone
+    fn send_command(&mut self, command: [Option<DshotCommand>;N]) {
+        self.push_frames(command.map(|command| command.map(DshotCommand::frame)));
+    }
+
+    /// Set the direction of rotation for each motor (DShot command 20 or 21),
+    /// sending the command as many times as the ESCs require
+    fn reverse(&mut self, reverse: [bool;N]) {
+        for _ in 0..DshotCommand::SpinDirectionNormal.repeats() {
+            self.push_frames(reverse.map(|reverse| Some(dshot::reverse(reverse))));
+        }
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    fn extended_telemetry(&mut self, enable: [bool;N]) {
+        for _ in 0..DshotCommand::ExtendedTelemetryEnable.repeats() {
+            self.push_frames(enable.map(|enable| Some(super::extended_telemetry(enable))));
+        }
     }
 
     /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;4]) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_clamp(throttle[1], false) as u32);
-        self.pio_instance.sm2.tx().push(dshot::throttle_clamp(throttle[2], false) as u32);
-        self.pio_instance.sm3.tx().push(dshot::throttle_clamp(throttle[3], false) as u32);
+    fn throttle_clamp(&mut self, throttle: [u16;N]) {
+        self.push_frames(throttle.map(|throttle| Some(dshot::throttle_clamp(throttle, false))));
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16;N], telemetry: [bool;N]) {
+        self.push_frames(core::array::from_fn(|i| Some(dshot::throttle_clamp(throttle[i], telemetry[i]))));
     }
 
     /// Set the throttle for each motor to zero (DShot command 48)
     fn throttle_minimum(&mut self) {
-        self.pio_instance.sm0.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm1.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm2.tx().push(dshot::throttle_minimum(false) as u32);
-        self.pio_instance.sm3.tx().push(dshot::throttle_minimum(false) as u32);
+        self.push_frames([Some(dshot::throttle_minimum(false));N]);
+    }
+
+    /// Parallel output does not support bidirectional DShot, so there is never any eRPM
+    fn erpm(&mut self) -> [Option<u32>;N] {
+        [None;N]
+    }
+
+    /// Parallel output does not support bidirectional DShot, so there is never any telemetry
+    fn telemetry(&mut self) -> [Option<Telemetry>;N] {
+        [None;N]
     }
 }
@@ -1,339 +1,1057 @@
-pub use super::DshotPioTrait;
-use dshot_encoder as dshot;
+pub use super::{DshotCommand, DshotError, DshotLocate, DshotMode, DshotPioTrait, DshotPioTry, DshotSpeed, DshotSync, DshotValue};
+use super::fifo::{self, DshotFifo};
+use super::parallel;
+use super::telemetry::Telemetry;
+use super::encoder as dshot;
 
-use rp2040_hal::{
-    gpio::{Function, Pin, PinId, PullType, ValidFunction},
-    pac::RESETS,
+use core::marker::PhantomData;
+
+use pio::{Instruction, InstructionOperands, JmpCondition, MovDestination, MovOperation, MovSource};
+use super::hal::{
+    clocks::ClocksManager,
+    dma::{single_buffer, ReadTarget, SingleChannel, WriteTarget},
+    gpio::{DynPinId, DynPullType, Function, Pin, PullType, PullUp, ValidFunction},
+    pac::{self, RESETS},
     pio::{
-        InstalledProgram, PIOBuilder, PIOExt, PinDir, ShiftDirection, StateMachineIndex, Tx,
-        UninitStateMachine, SM0, SM1, SM2, SM3,
+        Buffers, InstalledProgram, PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, ShiftDirection,
+        StateMachine, StateMachineIndex, Stopped, Tx, UninitStateMachine, ValidStateMachine, PIO, SM0,
+        SM1, SM2, SM3,
     },
+    Clock,
 };
 
-pub struct DshotPio<const N: usize, P: PIOExt> {
-    sm0: Tx<(P, SM0)>,
-    sm1: Tx<(P, SM1)>,
-    sm2: Tx<(P, SM2)>,
-    sm3: Tx<(P, SM3)>,
-}
-
-fn configure_pio_instance<P: PIOExt>(
-    pio_block: P,
-    resets: &mut RESETS,
-) -> (
-    InstalledProgram<P>,
-    (
-        UninitStateMachine<(P, SM0)>,
-        UninitStateMachine<(P, SM1)>,
-        UninitStateMachine<(P, SM2)>,
-        UninitStateMachine<(P, SM3)>,
-    ),
-) {
-    // Split the PIO block into individual state machines
-    let (mut pio, sm0, sm1, sm2, sm3) = pio_block.split(resets);
+pub struct DshotPio<const N: usize, P: PIOExt, S = ()> {
+    // Only owned when the driver was given the whole PIO block
+    pio: Option<PIO<P>>,
+    // State machines not given to the driver are left out
+    machine0: Option<Machine<(P, SM0)>>,
+    machine1: Option<Machine<(P, SM1)>>,
+    machine2: Option<Machine<(P, SM2)>>,
+    machine3: Option<Machine<(P, SM3)>>,
+    /// Index of the state machine driving each motor
+    state_machines: [u8; N],
+    pins: [DshotPioPin<P>; N],
+    program: InstalledProgram<P>,
+    mode: DshotMode,
+    /// Tuple of state machines given to a driver sharing its PIO block, `()` if given the whole block
+    given: PhantomData<fn() -> S>,
+}
+
+/// State machine driving a single motor, along with its FIFOs
+struct Machine<SM: ValidStateMachine> {
+    // Always stopped as far as rp2040-hal knows, as it is
+    // enabled through the CTRL register, to start them in phase
+    sm: StateMachine<SM, Stopped>,
+    rx: Rx<SM>,
+    tx: Tx<SM>,
+    // GPIO number relative to the GPIO base, if driving a motor at all
+    pin: Option<u8>,
+}
+
+impl<SM: ValidStateMachine> Machine<SM> {
+    /// Drive the pin low, and give back the stopped state machine
+    fn release(mut self) -> UninitStateMachine<SM> {
+        if let Some(pin) = self.pin {
+            self.sm.set_pins([(pin, PinState::Low)]);
+            self.sm.set_pindirs([(pin, PinDir::Output)]);
+        }
+        self.sm.uninit(self.rx, self.tx).0
+    }
+}
+
+/// Evaluate `$body` with `$machine` bound to state machine `$index` of the driver
+macro_rules! with_machine {
+    ($dshot:expr, $index:expr, |$machine:ident| $body:expr) => {
+        match $index {
+            0 => { let $machine = $dshot.machine0.as_mut().expect("State machine does not drive a motor"); $body }
+            1 => { let $machine = $dshot.machine1.as_mut().expect("State machine does not drive a motor"); $body }
+            2 => { let $machine = $dshot.machine2.as_mut().expect("State machine does not drive a motor"); $body }
+            3 => { let $machine = $dshot.machine3.as_mut().expect("State machine does not drive a motor"); $body }
+            _ => unreachable!("PIO blocks only have four state machines"),
+        }
+    };
+}
+
+/// Pin handed over to PIO block `P`, as given back when the driver is freed
+pub type DshotPioPin<P> = Pin<DynPinId, <P as PIOExt>::PinFunction, DynPullType>;
+
+/// A single pin which can be handed over to PIO block `P`
+pub trait DshotPin<P: PIOExt> {
+    /// Configure the pin for use with the PIO block
+    fn into_pio_pin(self, mode: DshotMode) -> DshotPioPin<P>;
+}
+
+impl<P: PIOExt, I: ValidFunction<P::PinFunction>, F: Function, T: PullType> DshotPin<P> for Pin<I, F, T> {
+    fn into_pio_pin(self, mode: DshotMode) -> DshotPioPin<P> {
+        // The ESC replies on the same pin in bidirectional mode, so it must idle high
+        let pin = self.into_function::<P::PinFunction>();
+        match mode {
+            DshotMode::Normal | DshotMode::Repeat { .. } => pin.into_pull_type::<DynPullType>().into_dyn_pin(),
+            DshotMode::Bidirectional => pin.into_pull_type::<PullUp>().into_pull_type::<DynPullType>().into_dyn_pin(),
+        }
+    }
+}
+
+/// Pins to output DShot on, given as a tuple of up to eight pins, or an array of pins of the same type
+pub trait DshotPins<P: PIOExt, const N: usize> {
+    /// Configure the pins for use with the PIO block
+    fn into_pio_pins(self, mode: DshotMode) -> [DshotPioPin<P>; N];
+}
+
+impl<P: PIOExt, A: DshotPin<P>, const N: usize> DshotPins<P, N> for [A; N] {
+    fn into_pio_pins(self, mode: DshotMode) -> [DshotPioPin<P>; N] {
+        self.map(|pin| pin.into_pio_pin(mode))
+    }
+}
+
+macro_rules! impl_dshot_pins {
+    ($n:literal, $($p:ident $i:tt),+) => {
+        impl<P: PIOExt, $($p: DshotPin<P>),+> DshotPins<P, $n> for ($($p,)+) {
+            fn into_pio_pins(self, mode: DshotMode) -> [DshotPioPin<P>; $n] {
+                [$(self.$i.into_pio_pin(mode)),+]
+            }
+        }
+    };
+}
+
+impl_dshot_pins!(1, A 0);
+impl_dshot_pins!(2, A 0, B 1);
+impl_dshot_pins!(3, A 0, B 1, C 2);
+impl_dshot_pins!(4, A 0, B 1, C 2, D 3);
+impl_dshot_pins!(5, A 0, B 1, C 2, D 3, E 4);
+impl_dshot_pins!(6, A 0, B 1, C 2, D 3, E 4, F 5);
+impl_dshot_pins!(7, A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_dshot_pins!(8, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
 
+/// Install the DShot program for the given mode into the PIO block
+fn install_program<P: PIOExt>(pio: &mut PIO<P>, mode: DshotMode) -> InstalledProgram<P> {
     // Program that generates DShot signal in PIO state machine
-    let dshot_pio_program = pio_proc::pio_asm!(
-        "entry:"
-        "   pull"
-        "   out null 16"
-        "   set x 15"
-        "loop:"
-        "   set pins 1"
-        "   out y 1"
-        "   jmp !y zero"
-        "   nop [2]"
-        "one:" // 6 and 2
-        "   set pins 0"
-        "   jmp x-- loop"
-        "   jmp reset"
-        "zero:" // 3 and 5
-        "   set pins 0 [3]"
-        "   jmp x-- loop"
-        "   jmp reset"
-        "reset:" // Blank frame
-        "   nop [31]"
-        "   nop [31]"
-        "   nop [31]"
-        "   jmp entry [31]"
-    );
+    let dshot_pio_program = match mode {
+        DshotMode::Normal => pio_proc::pio_asm!(
+            "entry:"
+            "   pull"
+            "   out null 16"
+            "   set x 15"
+            "loop:"
+            "   set pins 1"
+            "   out y 1"
+            "   jmp !y zero"
+            "   nop [2]"
+            "one:" // 6 and 2
+            "   set pins 0"
+            "   jmp x-- loop"
+            "   jmp reset"
+            "zero:" // 3 and 5
+            "   set pins 0 [3]"
+            "   jmp x-- loop"
+            "   jmp reset"
+            "reset:" // Blank frame
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   jmp entry [31]"
+        )
+        .program,
+        // Same timing as normal mode, but X keeps the last word, which `pull noblock`
+        // reloads whenever the TX FIFO is empty. The end of the frame is found from the
+        // output shift count instead, leaving Y for the bits and the blanking loop.
+        DshotMode::Repeat { .. } => pio_proc::pio_asm!(
+            "entry:"
+            "   pull noblock"
+            "   mov x osr"
+            "   out isr 16" // Upper half-word is the blanking loop count
+            "loop:"
+            "   set pins 1"
+            "   out y 1"
+            "   jmp !y zero"
+            "   nop [2]"
+            "one:" // 6 and 2
+            "   set pins 0"
+            "   jmp !osre loop"
+            "   jmp reset"
+            "zero:" // 3 and 5
+            "   set pins 0 [3]"
+            "   jmp !osre loop"
+            "reset:" // Blank frame
+            "   mov y isr [3]"
+            "blank:"
+            "   jmp y-- blank [7]"
+            "   jmp entry"
+        )
+        .program,
+        // 10 cycles per transmitted bit, 8 per received bit
+        DshotMode::Bidirectional => pio_proc::pio_asm!(
+            "entry:"
+            "   set pins 1" // Line idles high
+            "   set pindirs 1"
+            "   pull"
+            "   set x 15"
+            "tx_loop:"
+            "   set pins 0"
+            "   out y 1"
+            "   jmp !y tx_zero [1]"
+            "tx_one:" // 7 and 3
+            "   nop [2]"
+            "   set pins 1"
+            "   jmp x-- tx_loop [1]"
+            "   jmp rx"
+            "tx_zero:" // 4 and 6
+            "   set pins 1 [4]"
+            "   jmp x-- tx_loop"
+            "rx:" // Lower half-word is the reply timeout
+            "   out x 16"
+            "   set pindirs 0"
+            "wait_start:"
+            "   jmp pin still_high"
+            "   jmp rx_start"
+            "still_high:"
+            "   jmp x-- wait_start"
+            "   jmp entry" // No reply from ESC
+            "rx_start:" // Sample 21 bits at 5/4 bitrate
+            "   set x 20"
+            "rx_loop:"
+            "   in pins 1 [6]"
+            "   jmp x-- rx_loop"
+            "   push noblock" // Dropped if the RX FIFO is full, rather than stalling the frames
+        )
+        .program,
+    };
 
     // Install DShot program into PIO block
-    (
-        pio.install(&dshot_pio_program.program)
-            .expect("Unable to install program into PIO block"),
-        (sm0, sm1, sm2, sm3),
-    )
+    pio.install(&dshot_pio_program)
+        .expect("Unable to install program into PIO block")
 }
 
-///
-/// Defining constructor functions
-///
+//
+// Defining constructor functions
+//
 
 fn setup_state_machine<P: PIOExt, SM: StateMachineIndex>(
     installed: &InstalledProgram<P>,
     sm: UninitStateMachine<(P, SM)>,
     clk_div: (u16, u8),
-    pin: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-) -> Tx<(P, SM)> {
+    pin: Option<u8>,
+    mode: DshotMode,
+) -> Machine<(P, SM)> {
+    // SAFETY: The program is only uninstalled once all state machines are stopped
+    let builder = PIOBuilder::from_installed_program(unsafe { installed.share() });
 
-    // Configure pin for use with this PIO block
-    let pin = pin.into_function::<P::PinFunction>();
+    // Setup dummy program for unused state machines
+    let Some(pin) = pin else {
+        let (sm, rx, tx) = builder.build(sm);
+        return Machine { sm, rx, tx, pin: None };
+    };
 
-    // SAFETY: We never uninstall the program, so all unsafety considerations are met
-    let (mut smx, _, tx) = PIOBuilder::from_installed_program(unsafe { installed.share() })
-        .set_pins(pin.id().num, 1)
+    let (mut smx, rx, tx) = builder
+        .set_pins(pin, 1)
+        .in_pin_base(pin)
+        .jmp_pin(pin)
         .clock_divisor_fixed_point(clk_div.0, clk_div.1)
         .out_shift_direction(ShiftDirection::Left)
+        .in_shift_direction(ShiftDirection::Left)
         .pull_threshold(32)
-        .autopull(true)
+        // The repeating program must see the output shift register run empty
+        .autopull(!matches!(mode, DshotMode::Repeat { .. }))
         .build(sm);
 
-    smx.set_pindirs([(pin.id().num, PinDir::Output)]);
-    smx.start(); // NOTE: This consumes the state machine
-    tx
+    smx.set_pindirs([(pin, PinDir::Output)]);
+    Machine { sm: smx, rx, tx, pin: Some(pin) }
 }
 
-fn dummy_state_machine<P: PIOExt, SM: StateMachineIndex>(
-    installed: &InstalledProgram<P>,
-    sm: UninitStateMachine<(P, SM)>,
-) -> Tx<(P, SM)> {
-    let (_, _, tx) = PIOBuilder::from_installed_program(unsafe { installed.share() }).build(sm);
-    tx
+/// Configure the pins for use with PIO block `P`, also returning their
+/// GPIO numbers relative to the GPIO base of the PIO block. A driver sharing
+/// the block keeps the GPIO base it already has.
+fn into_gpio_pins<P: PIOExt, const N: usize>(
+    pins: impl DshotPins<P, N>,
+    mode: DshotMode,
+    shared: bool,
+) -> ([DshotPioPin<P>; N], [u8; N]) {
+    let pins = pins.into_pio_pins(mode);
+    let gpio = pins.each_ref().map(|pin| pin.id().num);
+    let base = match shared {
+        true => gpio_base::<P>(&gpio),
+        false => set_gpio_base::<P>(&gpio),
+    };
+    (pins, gpio.map(|pin| pin - base))
 }
 
-#[allow(dead_code)]
-impl<P: PIOExt> DshotPio<1, P> {
-    pub fn new<I0>(
-        pio_block: P,
-        resets: &mut RESETS,
-        pin0: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        clk_div: (u16, u8),
-    ) -> DshotPio<1, P> {
-        // Install DShot program into PIO block
-        let (installed, sm) = configure_pio_instance(pio_block, resets);
+/// State machines to drive DShot with, given as a tuple of one to four `UninitStateMachine`s
+/// of the same PIO block, in increasing order. Motor `i` is driven by the `i`th state machine.
+/// Sealed, as only the tuples implementing it here can be handed over to a driver.
+pub trait DshotStateMachines<P: PIOExt, const N: usize>: sealed::HandOver<P, N> {}
 
-        // Configure the state machine
-        let tx0 = setup_state_machine(&installed, sm.0, clk_div, pin0);
+mod sealed {
+    use super::{DshotPio, PIOExt};
 
-        // Setup dummy program for unused state machines
-        let tx1 = dummy_state_machine(&installed, sm.1);
-        let tx2 = dummy_state_machine(&installed, sm.2);
-        let tx3 = dummy_state_machine(&installed, sm.3);
+    /// Moving state machines in and out of a driver, out of reach outside the crate
+    pub trait HandOver<P: PIOExt, const N: usize>: Sized {
+        /// Configure the state machines for the given GPIOs, and hand them over to the driver
+        fn setup(self, dshot: &mut DshotPio<N, P, Self>, clk_div: (u16, u8), gpio: [u8; N]);
+        /// Take the stopped state machines back from the driver
+        fn release(dshot: &mut DshotPio<N, P, Self>) -> Self;
+    }
+}
 
-        // Return struct of four configured DShot state machines
-        DshotPio {
-            sm0: tx0,
-            sm1: tx1,
-            sm2: tx2,
-            sm3: tx3,
+macro_rules! impl_dshot_state_machines {
+    ($n:literal, $($machine:ident $sm:ident $index:literal $i:tt),+) => {
+        impl<P: PIOExt> DshotStateMachines<P, $n> for ($(UninitStateMachine<(P, $sm)>,)+) {}
+
+        impl<P: PIOExt> sealed::HandOver<P, $n> for ($(UninitStateMachine<(P, $sm)>,)+) {
+            fn setup(self, dshot: &mut DshotPio<$n, P, Self>, clk_div: (u16, u8), gpio: [u8; $n]) {
+                $(dshot.$machine = Some(setup_state_machine(&dshot.program, self.$i, clk_div, Some(gpio[$i]), dshot.mode));)+
+                dshot.state_machines = [$($index),+];
+            }
+
+            fn release(dshot: &mut DshotPio<$n, P, Self>) -> Self {
+                ($(dshot.$machine.take().expect("State machine does not drive a motor").release(),)+)
+            }
+        }
+    };
+}
+
+impl_dshot_state_machines!(1, machine0 SM0 0 0);
+impl_dshot_state_machines!(1, machine1 SM1 1 0);
+impl_dshot_state_machines!(1, machine2 SM2 2 0);
+impl_dshot_state_machines!(1, machine3 SM3 3 0);
+impl_dshot_state_machines!(2, machine0 SM0 0 0, machine1 SM1 1 1);
+impl_dshot_state_machines!(2, machine0 SM0 0 0, machine2 SM2 2 1);
+impl_dshot_state_machines!(2, machine0 SM0 0 0, machine3 SM3 3 1);
+impl_dshot_state_machines!(2, machine1 SM1 1 0, machine2 SM2 2 1);
+impl_dshot_state_machines!(2, machine1 SM1 1 0, machine3 SM3 3 1);
+impl_dshot_state_machines!(2, machine2 SM2 2 0, machine3 SM3 3 1);
+impl_dshot_state_machines!(3, machine0 SM0 0 0, machine1 SM1 1 1, machine2 SM2 2 2);
+impl_dshot_state_machines!(3, machine0 SM0 0 0, machine1 SM1 1 1, machine3 SM3 3 2);
+impl_dshot_state_machines!(3, machine0 SM0 0 0, machine2 SM2 2 1, machine3 SM3 3 2);
+impl_dshot_state_machines!(3, machine1 SM1 1 0, machine2 SM2 2 1, machine3 SM3 3 2);
+impl_dshot_state_machines!(4, machine0 SM0 0 0, machine1 SM1 1 1, machine2 SM2 2 2, machine3 SM3 3 3);
+
+/// Bits of the PIO CTRL register
+const CTRL_SM_ENABLE: u32 = 0;
+const CTRL_SM_RESTART: u32 = 4;
+const CTRL_CLKDIV_RESTART: u32 = 8;
+
+/// Registers of PIO block `P`, for the accesses the HAL has no API for
+fn pio_block<P: PIOExt>() -> &'static pac::pio0::RegisterBlock {
+    // SAFETY: Callers only touch registers and bits of the state machines owned by the driver
+    unsafe {
+        &*match P::id() {
+            0 => pac::PIO0::ptr(),
+            #[cfg(feature = "rp235x-hal")]
+            2 => pac::PIO2::ptr(),
+            _ => pac::PIO1::ptr(),
         }
     }
 }
 
-#[allow(dead_code)]
-impl<P: PIOExt> DshotPio<2, P> {
+/// Set or clear bits of the CTRL register of PIO block `P`, through its atomic aliases
+fn ctrl_modify<P: PIOExt>(set: bool, bits: u32) {
+    let ctrl = pio_block::<P>().ctrl().as_ptr();
+    let alias = match set {
+        true => 0x2000,
+        false => 0x3000,
+    };
+
+    // SAFETY: Writes to the atomic aliases only affect the given bits, which
+    // belong to state machines owned by the driver
+    unsafe { ((ctrl as usize + alias) as *mut u32).write_volatile(bits) }
+}
+
+/// Select which 32 GPIOs PIO block `P` can access, returning the offset to subtract
+/// from the GPIO numbers. The RP2350B has 48 GPIOs, of which a PIO block either
+/// sees 0 to 31, or 16 to 47, while the other chips always use 0 to 31.
+#[cfg_attr(not(feature = "rp235x-hal"), allow(clippy::extra_unused_type_parameters))]
+fn set_gpio_base<P: PIOExt>(pins: &[u8]) -> u8 {
+    #[cfg(feature = "rp235x-hal")]
+    {
+        let base = match pins.iter().any(|&pin| pin >= 32) {
+            true => 16,
+            false => 0,
+        };
+        assert!(
+            pins.iter().all(|&pin| pin >= base),
+            "Pins of a PIO block must all be within GPIO 0-31 or GPIO 16-47"
+        );
+        pio_block::<P>().gpiobase().write(|w| unsafe { w.bits(base as u32) });
+        base
+    }
+
+    #[cfg(not(feature = "rp235x-hal"))]
+    {
+        let _ = pins;
+        0
+    }
+}
+
+/// GPIO base PIO block `P` already has, checking that the pins are within reach of it.
+/// Moving the base would also move the pins of any other programs on the block.
+#[cfg_attr(not(feature = "rp235x-hal"), allow(clippy::extra_unused_type_parameters))]
+fn gpio_base<P: PIOExt>(pins: &[u8]) -> u8 {
+    #[cfg(feature = "rp235x-hal")]
+    {
+        let base = pio_block::<P>().gpiobase().read().bits() as u8;
+        assert!(
+            pins.iter().all(|&pin| pin >= base && pin < base + 32),
+            "Pins of a shared PIO block must be within reach of its GPIO base"
+        );
+        base
+    }
+
+    #[cfg(not(feature = "rp235x-hal"))]
+    {
+        let _ = pins;
+        0
+    }
+}
+
+impl<const N: usize, P: PIOExt> DshotPio<N, P> {
     pub fn new(
         pio_block: P,
         resets: &mut RESETS,
-        pin0: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin1: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
+        pins: impl DshotPins<P, N>,
         clk_div: (u16, u8),
-    ) -> DshotPio<2, P> {
-        // Install DShot program into PIO block
-        let (installed, sm) = configure_pio_instance(pio_block, resets);
+    ) -> DshotPio<N, P> {
+        Self::new_with_mode(pio_block, resets, pins, clk_div, DshotMode::Normal)
+    }
 
-        // Configure the state machine
-        let tx0 = setup_state_machine(&installed, sm.0, clk_div, pin0);
-        let tx1 = setup_state_machine(&installed, sm.1, clk_div, pin1);
+    /// Bidirectional DShot, where eRPM replies are read back through `erpm`.
+    /// Uses 10 PIO cycles per bit rather than 8 when computing the clock divider.
+    pub fn new_bidirectional(
+        pio_block: P,
+        resets: &mut RESETS,
+        pins: impl DshotPins<P, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<N, P> {
+        Self::new_with_mode(pio_block, resets, pins, clk_div, DshotMode::Bidirectional)
+    }
 
-        // Setup dummy program for unused state machines
-        let tx2 = dummy_state_machine(&installed, sm.2);
-        let tx3 = dummy_state_machine(&installed, sm.3);
+    /// Like `new`, but computing the clock divider for the given DShot speed from the system clock
+    pub fn with_speed(
+        pio_block: P,
+        resets: &mut RESETS,
+        pins: impl DshotPins<P, N>,
+        speed: DshotSpeed,
+        clocks: &ClocksManager,
+    ) -> Result<DshotPio<N, P>, DshotError> {
+        let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+        let clk_div = speed.clock_divider(sys_clk_hz, DshotMode::Normal)?;
+        Ok(Self::new_with_mode(pio_block, resets, pins, clk_div, DshotMode::Normal))
+    }
 
-        // Return struct of four configured DShot state machines
-        DshotPio {
-            sm0: tx0,
-            sm1: tx1,
-            sm2: tx2,
-            sm3: tx3,
-        }
+    /// Like `new_bidirectional`, but computing the clock divider
+    /// for the given DShot speed from the system clock
+    pub fn with_speed_bidirectional(
+        pio_block: P,
+        resets: &mut RESETS,
+        pins: impl DshotPins<P, N>,
+        speed: DshotSpeed,
+        clocks: &ClocksManager,
+    ) -> Result<DshotPio<N, P>, DshotError> {
+        let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+        let clk_div = speed.clock_divider(sys_clk_hz, DshotMode::Bidirectional)?;
+        Ok(Self::new_with_mode(pio_block, resets, pins, clk_div, DshotMode::Bidirectional))
     }
-}
 
-#[allow(dead_code)]
-impl<P: PIOExt> DshotPio<3, P> {
-    pub fn new(
+    /// Like `new`, with the given signalling mode. Used for `DshotMode::Repeat`, where the state
+    /// machines keep resending the last frame, so that the ESCs see a steady frame rate however
+    /// irregularly the CPU pushes frames.
+    pub fn new_with_mode(
         pio_block: P,
         resets: &mut RESETS,
-        pin0: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin1: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin2: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
+        pins: impl DshotPins<P, N>,
         clk_div: (u16, u8),
-    ) -> DshotPio<3, P> {
+        mode: DshotMode,
+    ) -> DshotPio<N, P> {
+        let () = Self::VALID_N;
+
         // Install DShot program into PIO block
-        let (installed, sm) = configure_pio_instance(pio_block, resets);
+        let (mut pio, sm0, sm1, sm2, sm3) = pio_block.split(resets);
+        let program = install_program(&mut pio, mode);
+        let (pins, gpio) = into_gpio_pins(pins, mode, false);
+        let mut dshot = DshotPio::empty(program, pins, mode);
 
-        // Configure the state machine
-        let tx0 = setup_state_machine(&installed, sm.0, clk_div, pin0);
-        let tx1 = setup_state_machine(&installed, sm.1, clk_div, pin1);
-        let tx2 = setup_state_machine(&installed, sm.2, clk_div, pin2);
+        // Configure a state machine for each pin, the rest are left unused
+        dshot.machine0 = Some(setup_state_machine(&dshot.program, sm0, clk_div, gpio.first().copied(), mode));
+        dshot.machine1 = Some(setup_state_machine(&dshot.program, sm1, clk_div, gpio.get(1).copied(), mode));
+        dshot.machine2 = Some(setup_state_machine(&dshot.program, sm2, clk_div, gpio.get(2).copied(), mode));
+        dshot.machine3 = Some(setup_state_machine(&dshot.program, sm3, clk_div, gpio.get(3).copied(), mode));
+        dshot.state_machines = core::array::from_fn(|sm| sm as u8);
+        dshot.pio = Some(pio);
 
-        // Setup dummy program for unused state machines
-        let tx3 = dummy_state_machine(&installed, sm.3);
+        dshot.start_in_phase();
+        dshot
+    }
+
+    /// Like `new`, but sharing the PIO block with other programs. Only the given state machines
+    /// are used, and the DShot program takes up only part of the instruction memory, so the
+    /// rest of the block remains available through `pio` and the other state machines.
+    pub fn from_split<S: DshotStateMachines<P, N>>(
+        pio: &mut PIO<P>,
+        state_machines: S,
+        pins: impl DshotPins<P, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<N, P, S> {
+        Self::from_split_with_mode(pio, state_machines, pins, clk_div, DshotMode::Normal)
+    }
 
-        // Return struct of four configured DShot state machines
+    /// Like `new_bidirectional`, but sharing the PIO block with other programs, see `from_split`
+    pub fn from_split_bidirectional<S: DshotStateMachines<P, N>>(
+        pio: &mut PIO<P>,
+        state_machines: S,
+        pins: impl DshotPins<P, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPio<N, P, S> {
+        Self::from_split_with_mode(pio, state_machines, pins, clk_div, DshotMode::Bidirectional)
+    }
+
+    /// Like `new_with_mode`, but sharing the PIO block with other programs, see `from_split`
+    pub fn from_split_with_mode<S: DshotStateMachines<P, N>>(
+        pio: &mut PIO<P>,
+        state_machines: S,
+        pins: impl DshotPins<P, N>,
+        clk_div: (u16, u8),
+        mode: DshotMode,
+    ) -> DshotPio<N, P, S> {
+        let () = Self::VALID_N;
+
+        let program = install_program(pio, mode);
+        let (pins, gpio) = into_gpio_pins(pins, mode, true);
+        let mut dshot = DshotPio::empty(program, pins, mode);
+
+        sealed::HandOver::setup(state_machines, &mut dshot, clk_div, gpio);
+
+        dshot.start_in_phase();
+        dshot
+    }
+
+    /// Stop the state machines, drive the pins low and uninstall the program, giving back
+    /// the PIO block and the pins. Drivers sharing their PIO block are given back with `release`.
+    pub fn free(mut self) -> (P, [DshotPioPin<P>; N]) {
+        let mut pio = self.pio.take().expect("PIO block owned by the driver");
+        self.halt();
+
+        let sm0 = self.machine0.take().expect("PIO block owned by the driver").release();
+        let sm1 = self.machine1.take().expect("PIO block owned by the driver").release();
+        let sm2 = self.machine2.take().expect("PIO block owned by the driver").release();
+        let sm3 = self.machine3.take().expect("PIO block owned by the driver").release();
+        pio.uninstall(self.program);
+
+        (pio.free(sm0, sm1, sm2, sm3), self.pins)
+    }
+}
+
+impl<const N: usize, P: PIOExt, S: DshotStateMachines<P, N>> DshotPio<N, P, S> {
+    /// Like `free`, but for drivers sharing their PIO block, see `from_split`. Gives back
+    /// the state machines, as the same tuple they were given as, and the pins.
+    pub fn release(mut self, pio: &mut PIO<P>) -> (S, [DshotPioPin<P>; N]) {
+        self.halt();
+
+        let state_machines = sealed::HandOver::release(&mut self);
+        pio.uninstall(self.program);
+
+        (state_machines, self.pins)
+    }
+}
+
+impl<const N: usize, P: PIOExt, S> DshotPio<N, P, S> {
+    /// A PIO block only has four state machines to drive DShot with
+    const VALID_N: () = assert!(N >= 1 && N <= 4, "DshotPio supports 1 to 4 motors per PIO block");
+
+    /// Driver without any state machines yet
+    fn empty(program: InstalledProgram<P>, pins: [DshotPioPin<P>; N], mode: DshotMode) -> DshotPio<N, P, S> {
         DshotPio {
-            sm0: tx0,
-            sm1: tx1,
-            sm2: tx2,
-            sm3: tx3,
+            pio: None,
+            machine0: None,
+            machine1: None,
+            machine2: None,
+            machine3: None,
+            state_machines: [0; N],
+            pins,
+            program,
+            mode,
+            given: PhantomData,
+        }
+    }
+
+    /// Change the DShot speed at runtime, see `set_clock_divider`
+    pub fn set_speed(&mut self, speed: DshotSpeed, clocks: &ClocksManager) -> Result<(), DshotError> {
+        let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+        self.set_clock_divider(speed.clock_divider(sys_clk_hz, self.mode)?);
+        Ok(())
+    }
+
+    /// Change the clock divider at runtime. The state machines are stopped, any frames
+    /// not yet sent are dropped, and they are then restarted in phase at the new speed.
+    pub fn set_clock_divider(&mut self, clk_div: (u16, u8)) {
+        // Stop first, so that no frame goes out partly at the old speed and partly at the new one
+        self.halt();
+        for sm in self.state_machines {
+            with_machine!(self, sm, |machine| machine.sm.clock_divisor_fixed_point(clk_div.0, clk_div.1));
+        }
+        self.start();
+    }
+
+    /// Restart the state machines so that all motors' frames start on the same cycle again.
+    /// Any frames not yet sent are dropped.
+    pub fn resync(&mut self) {
+        self.start_in_phase();
+    }
+
+    /// Bit mask of the state machines in use
+    fn mask(&self) -> u32 {
+        self.state_machines.iter().fold(0, |mask, &sm| mask | 1 << sm)
+    }
+
+    /// Stop the state machines in use, empty their FIFOs and move them back to the start
+    /// of the program, then enable them and restart their clock dividers on the same cycle
+    fn start_in_phase(&mut self) {
+        self.halt();
+        self.start();
+    }
+}
+
+impl<const N: usize, P: PIOExt, S> super::DshotSync for DshotPio<N, P, S> {
+    fn halt(&mut self) {
+        ctrl_modify::<P>(false, self.mask() << CTRL_SM_ENABLE);
+
+        let entry = self.program.offset();
+        for sm in self.state_machines {
+            with_machine!(self, sm, |machine| {
+                machine.sm.clear_fifos();
+                // The repeating program would otherwise resend the last frame left in X
+                machine.sm.exec_instruction(Instruction {
+                    operands: InstructionOperands::MOV {
+                        destination: MovDestination::X,
+                        op: MovOperation::None,
+                        source: MovSource::NULL,
+                    },
+                    delay: 0,
+                    side_set: None,
+                });
+                machine.sm.exec_instruction(Instruction {
+                    operands: InstructionOperands::JMP {
+                        condition: JmpCondition::Always,
+                        address: entry,
+                    },
+                    delay: 0,
+                    side_set: None,
+                });
+            });
         }
     }
+
+    fn start(&mut self) {
+        let mask = self.mask();
+        ctrl_modify::<P>(
+            true,
+            mask << CTRL_SM_ENABLE | mask << CTRL_SM_RESTART | mask << CTRL_CLKDIV_RESTART,
+        );
+    }
 }
 
-#[allow(dead_code)]
-impl<P: PIOExt> DshotPio<4, P> {
-    pub fn new(
-        pio_block: P,
-        resets: &mut RESETS,
-        pin0: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin1: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin2: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        pin3: Pin<impl PinId + ValidFunction<P::PinFunction>, impl Function, impl PullType>,
-        clk_div: (u16, u8),
-    ) -> DshotPio<4, P> {
-        // Install DShot program into PIO block
-        let (installed, sm) = configure_pio_instance(pio_block, resets);
+impl<const N: usize, P: PIOExt, S> super::DshotLocate for DshotPio<N, P, S> {
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        (P::id(), self.state_machines[motor] as usize)
+    }
+}
 
-        // Configure the state machine
-        let tx0 = setup_state_machine(&installed, sm.0, clk_div, pin0);
-        let tx1 = setup_state_machine(&installed, sm.1, clk_div, pin1);
-        let tx2 = setup_state_machine(&installed, sm.2, clk_div, pin2);
-        let tx3 = setup_state_machine(&installed, sm.3, clk_div, pin3);
+//
+// DMA frame upload
+//
 
-        // Return struct of four configured DShot state machines
-        DshotPio {
-            sm0: tx0,
-            sm1: tx1,
-            sm2: tx2,
-            sm3: tx3,
+/// TX FIFO of a single motor, as the target of a DMA transfer paced by its DREQ
+pub struct DmaTx<'a, SM: ValidStateMachine> {
+    tx: &'a mut Tx<SM>,
+}
+
+// SAFETY: This only writes to the TX FIFO, the same way as the `WriteTarget` impl of `Tx`
+unsafe impl<SM: ValidStateMachine> WriteTarget for DmaTx<'_, SM> {
+    type TransmittedWord = u32;
+
+    fn tx_treq() -> Option<u8> {
+        Some(SM::tx_dreq())
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        (self.tx.fifo_address() as u32, u32::MAX)
+    }
+
+    fn tx_increment(&self) -> bool {
+        false
+    }
+}
+
+/// TX FIFOs of the state machines driving the motors of a driver, split out of it
+/// so that DMA transfers to several motors can run alongside each other
+pub struct DmaTargets<'a, P: PIOExt> {
+    tx0: Option<DmaTx<'a, (P, SM0)>>,
+    tx1: Option<DmaTx<'a, (P, SM1)>>,
+    tx2: Option<DmaTx<'a, (P, SM2)>>,
+    tx3: Option<DmaTx<'a, (P, SM3)>>,
+}
+
+impl<'a, P: PIOExt> DmaTargets<'a, P> {
+    /// Start uploading a buffer of encoded words to the motor driven by state machine `SM`.
+    /// The transfer is paced by the TX FIFO, so each word is sent as its own DShot frame.
+    /// Call `wait` on the returned transfer to get back the channel and buffer.
+    ///
+    /// Panics if `SM` does not drive a motor, or already has a transfer.
+    pub fn dma_push<SM, CH, B>(&mut self, ch: CH, words: B) -> single_buffer::Transfer<CH, B, DmaTx<'a, (P, SM)>>
+    where
+        SM: DshotStateMachine,
+        CH: SingleChannel,
+        B: ReadTarget<ReceivedWord = u32>,
+    {
+        single_buffer::Config::new(ch, words, SM::take(self)).start()
+    }
+}
+
+/// State machine driving a single motor, selecting which TX FIFO `dma_push` uploads to
+pub trait DshotStateMachine: StateMachineIndex + Sized {
+    fn take<'a, P: PIOExt>(targets: &mut DmaTargets<'a, P>) -> DmaTx<'a, (P, Self)>;
+}
+
+macro_rules! impl_dshot_state_machine {
+    ($sm:ident, $tx:ident) => {
+        impl DshotStateMachine for $sm {
+            fn take<'a, P: PIOExt>(targets: &mut DmaTargets<'a, P>) -> DmaTx<'a, (P, $sm)> {
+                targets.$tx.take().expect("State machine does not drive a motor, or already has a transfer")
+            }
+        }
+    };
+}
+
+impl_dshot_state_machine!(SM0, tx0);
+impl_dshot_state_machine!(SM1, tx1);
+impl_dshot_state_machine!(SM2, tx2);
+impl_dshot_state_machine!(SM3, tx3);
+
+impl<const N: usize, P: PIOExt, S> DshotPio<N, P, S> {
+    /// Encode a special command or throttle value into the word expected by the state machines,
+    /// for filling buffers to upload with `dma_push`
+    pub fn encode(&self, value: DshotValue, telemetry: bool) -> u32 {
+        self.mode.tx_word(value.frame(telemetry))
+    }
+
+    /// Split out the TX FIFOs of the state machines, to upload buffers to several motors at once,
+    /// one DMA channel per motor. The driver is borrowed until every transfer has been waited for.
+    pub fn dma_targets(&mut self) -> DmaTargets<'_, P> {
+        DmaTargets {
+            tx0: self.machine0.as_mut().map(|machine| DmaTx { tx: &mut machine.tx }),
+            tx1: self.machine1.as_mut().map(|machine| DmaTx { tx: &mut machine.tx }),
+            tx2: self.machine2.as_mut().map(|machine| DmaTx { tx: &mut machine.tx }),
+            tx3: self.machine3.as_mut().map(|machine| DmaTx { tx: &mut machine.tx }),
         }
     }
+
+    /// Start uploading a buffer of encoded words to the motor driven by state machine `SM`,
+    /// see `DmaTargets::dma_push`. Only a single transfer can run at a time this way, use
+    /// `dma_targets` to upload to several motors at once.
+    pub fn dma_push<SM, CH, B>(
+        &mut self,
+        ch: CH,
+        words: B,
+    ) -> single_buffer::Transfer<CH, B, DmaTx<'_, (P, SM)>>
+    where
+        SM: DshotStateMachine,
+        CH: SingleChannel,
+        B: ReadTarget<ReceivedWord = u32>,
+    {
+        self.dma_targets().dma_push(ch, words)
+    }
 }
 
-///
-/// Implementing DshotPioTrait
-///
+//
+// Non-blocking sending
+//
 
-impl<P: PIOExt> super::DshotPioTrait<1> for DshotPio<1, P> {
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 1]) {
-        self.sm0.write(command[0].min(dshot::THROTTLE_MAX) as u32);
+impl<const N: usize, P: PIOExt, S> DshotPio<N, P, S> {
+    /// Send a special command or throttle value to each motor, unless a TX FIFO is full
+    pub fn try_command(
+        &mut self,
+        command: [DshotValue; N],
+        telemetry: [bool; N],
+    ) -> Result<(), DshotError> {
+        let words: [u32; N] = core::array::from_fn(|i| self.mode.tx_word(command[i].frame(telemetry[i])));
+        self.try_dispatch(words)
     }
 
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool; 1]) {
-        self.sm0.write(dshot::reverse(reverse[0]) as u32);
+    /// Set the throttle for each motor, clamped between 48 and 2047, unless a TX FIFO is full
+    pub fn try_throttle_clamp(&mut self, throttle: [u16; N]) -> Result<(), DshotError> {
+        let words = throttle.map(|throttle| self.mode.tx_word(dshot::throttle_clamp(throttle, false)));
+        self.try_dispatch(words)
     }
 
-    /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;1])  {
-        self.sm0.write(dshot::throttle_clamp(throttle[0], false) as u32);
+    /// Set the throttle for each motor to zero (DShot command 48), unless a TX FIFO is full
+    pub fn try_throttle_minimum(&mut self) -> Result<(), DshotError> {
+        let word = self.mode.tx_word(dshot::throttle_minimum(false));
+        self.try_dispatch([word; N])
     }
 
-    /// Set the throttle for each motor to zero (DShot command 48)
-    fn throttle_minimum(&mut self) {
-        self.sm0.write(dshot::throttle_minimum(false) as u32);
+    /// Push word `i` into the TX FIFO of state machine `i`, but only
+    /// if every FIFO has room, so that motors are never updated partially
+    fn try_dispatch(&mut self, words: [u32; N]) -> Result<(), DshotError> {
+        if let Some(motor) = (0..N).find(|&motor| self.is_full(motor)) {
+            return Err(DshotError::FifoFull { motor });
+        }
+        fifo::dispatch(self, words);
+        Ok(())
     }
 }
 
-impl<P: PIOExt> super::DshotPioTrait<2> for DshotPio<2, P> {
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 2]) {
-        self.sm0.write(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[1].min(dshot::THROTTLE_MAX) as u32);
+impl<const N: usize, P: PIOExt, S> super::DshotPioTry<N> for DshotPio<N, P, S> {
+    /// Unlike the inherent `try_command`, each motor with room in its TX FIFO is sent its
+    /// frame, so that a single full FIFO does not hold back the other motors
+    fn try_command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) -> [bool; N] {
+        let mut dropped = [false; N];
+        for (motor, dropped) in dropped.iter_mut().enumerate() {
+            *dropped = self.is_full(motor);
+            if !*dropped {
+                self.push(motor, self.mode.tx_word(command[motor].frame(telemetry[motor])));
+            }
+        }
+        dropped
     }
+}
+
+//
+// Implementing DshotPioTrait
+//
 
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool; 2]) {
-        self.sm0.write(dshot::reverse(reverse[0]) as u32);
-        self.sm1.write(dshot::reverse(reverse[1]) as u32);
+impl<const N: usize, P: PIOExt, S> fifo::DshotFifo for DshotPio<N, P, S> {
+    fn mode(&self) -> DshotMode {
+        self.mode
     }
 
-    /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;2]) {
-        self.sm0.write(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.sm1.write(dshot::throttle_clamp(throttle[1], false) as u32);
+    fn is_full(&mut self, motor: usize) -> bool {
+        with_machine!(self, self.state_machines[motor], |machine| machine.tx.is_full())
     }
 
-    /// Set the throttle for each motor to zero (DShot command 48)
-    fn throttle_minimum(&mut self) {
-        self.sm0.write(dshot::throttle_minimum(false) as u32);
-        self.sm1.write(dshot::throttle_minimum(false) as u32);
+    fn push(&mut self, motor: usize, word: u32) {
+        with_machine!(self, self.state_machines[motor], |machine| machine.tx.write(word));
+    }
+
+    fn pull(&mut self, motor: usize) -> Option<u32> {
+        with_machine!(self, self.state_machines[motor], |machine| machine.rx.read())
     }
 }
 
-impl<P: PIOExt> super::DshotPioTrait<3> for DshotPio<3, P> {
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 3]) {
-        self.sm0.write(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[1].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[2].min(dshot::THROTTLE_MAX) as u32);
+impl<const N: usize, P: PIOExt, S> super::DshotPioTrait<N> for DshotPio<N, P, S> {
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) {
+        fifo::command(self, command, telemetry);
     }
 
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool; 3]) {
-        self.sm0.write(dshot::reverse(reverse[0]) as u32);
-        self.sm1.write(dshot::reverse(reverse[1]) as u32);
-        self.sm2.write(dshot::reverse(reverse[2]) as u32);
+    /// Send a special command to each motor, motors given `None` are left alone
+    fn send_command(&mut self, command: [Option<DshotCommand>; N]) {
+        fifo::send_command(self, command);
+    }
+
+    /// Set the direction of rotation for each motor (DShot command 20 or 21),
+    /// sending the command as many times as the ESCs require
+    fn reverse(&mut self, reverse: [bool; N]) {
+        fifo::reverse(self, reverse);
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    fn extended_telemetry(&mut self, enable: [bool; N]) {
+        fifo::extended_telemetry(self, enable);
     }
 
     /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;3]) {
-        self.sm0.write(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.sm1.write(dshot::throttle_clamp(throttle[1], false) as u32);
-        self.sm2.write(dshot::throttle_clamp(throttle[2], false) as u32);
+    fn throttle_clamp(&mut self, throttle: [u16; N]) {
+        fifo::throttle_clamp(self, throttle, [false; N]);
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16; N], telemetry: [bool; N]) {
+        fifo::throttle_clamp(self, throttle, telemetry);
     }
 
     /// Set the throttle for each motor to zero (DShot command 48)
     fn throttle_minimum(&mut self) {
-        self.sm0.write(dshot::throttle_minimum(false) as u32);
-        self.sm1.write(dshot::throttle_minimum(false) as u32);
-        self.sm2.write(dshot::throttle_minimum(false) as u32);
+        fifo::throttle_minimum::<N>(self);
+    }
+
+    /// Get the latest eRPM reported by each motor, if in bidirectional mode. The RX FIFO
+    /// holds 4 replies, and newer replies are dropped while it is full, so this should be
+    /// called every frame for the value to be the latest one.
+    fn erpm(&mut self) -> [Option<u32>; N] {
+        core::array::from_fn(|motor| fifo::latest_erpm(self, motor))
+    }
+
+    /// Get the oldest unread reply from each motor, if in bidirectional mode. Unlike
+    /// `erpm`, this does not discard older replies, and also yields EDT frames.
+    fn telemetry(&mut self) -> [Option<Telemetry>; N] {
+        core::array::from_fn(|motor| fifo::next_telemetry(self, motor))
     }
 }
 
-impl<P: PIOExt> super::DshotPioTrait<4> for DshotPio<4, P> {
-    /// Send any valid DShot value to the ESC.
-    fn command(&mut self, command: [u16; 4]) {
-        self.sm0.write(command[0].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[1].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[2].min(dshot::THROTTLE_MAX) as u32);
-        self.sm0.write(command[3].min(dshot::THROTTLE_MAX) as u32);
+//
+// Parallel output from a single state machine
+//
+
+/// Drives N (1 to 8) motors on consecutive pins from a single state machine, leaving the
+/// other state machines of the PIO block free. All motors' edges are perfectly in sync.
+/// Only supports regular DShot, and uses 16 PIO cycles per bit when computing the clock divider.
+pub struct DshotPioParallel<const N: usize, P: PIOExt, SM: StateMachineIndex> {
+    tx: Tx<(P, SM)>,
+    rx: Rx<(P, SM)>,
+    sm: StateMachine<(P, SM), Running>,
+    pins: [DshotPioPin<P>; N],
+    // GPIO numbers relative to the GPIO base
+    gpio: [u8; N],
+}
+
+impl<const N: usize, P: PIOExt, SM: StateMachineIndex> DshotPioParallel<N, P, SM> {
+    /// A state machine can drive at most eight pins in parallel
+    const VALID_N: () = assert!(N >= 1 && N <= parallel::MAX_MOTORS, "DshotPioParallel supports 1 to 8 motors");
+
+    /// Install the parallel program into the PIO block, and start it on the given state machine.
+    /// The pins must be consecutive, in increasing order.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        pins: impl DshotPins<P, N>,
+        clk_div: (u16, u8),
+    ) -> DshotPioParallel<N, P, SM> {
+        let () = Self::VALID_N;
+
+        let (pins, gpio) = into_gpio_pins(pins, DshotMode::Normal, true);
+        assert!(
+            gpio.windows(2).all(|pair| pair[1] == pair[0] + 1),
+            "DshotPioParallel pins must be consecutive"
+        );
+
+        // Program with 16 cycles per bit. Stalls only happen while the lines are low.
+        let dshot_pio_program = pio_proc::pio_asm!(
+            "entry:"
+            "   out isr 32" // Mask of motors receiving a frame
+            "   set x 15"
+            "loop:"
+            "   out y 8"
+            "   mov pins isr [5]"
+            "   mov pins y [5]" // 12 and 4 for ones, 6 and 10 for zeros
+            "   mov pins null [1]"
+            "   jmp x-- loop"
+            "reset:" // Blank frame
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   nop [31]"
+            "   jmp entry [31]"
+        );
+        let installed = pio
+            .install(&dshot_pio_program.program)
+            .expect("Unable to install program into PIO block");
+
+        // The RX FIFO is unused, so join it to the TX FIFO to hold a frame and a half
+        let (mut smx, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .out_pins(gpio[0], N as u8)
+            .clock_divisor_fixed_point(clk_div.0, clk_div.1)
+            .out_shift_direction(ShiftDirection::Right)
+            .pull_threshold(32)
+            .autopull(true)
+            .buffers(Buffers::OnlyTx)
+            .build(sm);
+
+        smx.set_pindirs(gpio.map(|pin| (pin, PinDir::Output)));
+        DshotPioParallel { tx, rx, sm: smx.start(), pins, gpio }
     }
 
-    /// Set the direction of rotation for each motor
-    fn reverse(&mut self, reverse: [bool; 4]) {
-        self.sm0.write(dshot::reverse(reverse[0]) as u32);
-        self.sm1.write(dshot::reverse(reverse[1]) as u32);
-        self.sm2.write(dshot::reverse(reverse[2]) as u32);
-        self.sm3.write(dshot::reverse(reverse[3]) as u32);
+    /// Stop the state machine, drive the pins low and uninstall the program,
+    /// giving back the state machine and the pins. Any frames not yet sent are dropped.
+    pub fn release(self, pio: &mut PIO<P>) -> (UninitStateMachine<(P, SM)>, [DshotPioPin<P>; N]) {
+        let mut sm = self.sm.stop();
+        sm.set_pins(self.gpio.map(|pin| (pin, PinState::Low)));
+
+        let (sm, program) = sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+
+        (sm, self.pins)
+    }
+
+    /// Send a frame to each motor given `Some`, leaving the others alone. Unlike `DshotPio`,
+    /// this waits for room in the FIFO, as a partially sent frame would garble the next ones.
+    fn push_frames(&mut self, frames: [Option<u16>; N]) {
+        let words = parallel::frame_words(frames);
+
+        // Write the whole frame at once, as the program would otherwise stall halfway
+        // through it while the CPU is interrupted, stretching one of the bits
+        while self.tx_level() > parallel::FIFO_DEPTH - parallel::FRAME_WORDS {}
+        critical_section::with(|_| {
+            for word in words {
+                self.tx.write(word);
+            }
+        });
+    }
+
+    /// Number of words waiting in the TX FIFO
+    fn tx_level(&self) -> usize {
+        let flevel = pio_block::<P>().flevel().read().bits();
+        ((flevel >> (8 * SM::id())) & 0x0F) as usize
+    }
+}
+
+impl<const N: usize, P: PIOExt, SM: StateMachineIndex> super::DshotLocate for DshotPioParallel<N, P, SM> {
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        assert!(motor < N, "Motor index out of range");
+        (P::id(), SM::id())
+    }
+}
+
+impl<const N: usize, P: PIOExt, SM: StateMachineIndex> super::DshotPioTrait<N> for DshotPioParallel<N, P, SM> {
+    /// Send a special command or throttle value to each motor, optionally requesting telemetry
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) {
+        self.push_frames(core::array::from_fn(|i| Some(command[i].frame(telemetry[i]))));
+    }
+
+    /// Send a special command to each motor, motors given `None` are left alone
+    fn send_command(&mut self, command: [Option<DshotCommand>; N]) {
+        self.push_frames(command.map(|command| command.map(DshotCommand::frame)));
+    }
+
+    /// Set the direction of rotation for each motor (DShot command 20 or 21),
+    /// sending the command as many times as the ESCs require
+    fn reverse(&mut self, reverse: [bool; N]) {
+        for _ in 0..DshotCommand::SpinDirectionNormal.repeats() {
+            self.push_frames(reverse.map(|reverse| Some(dshot::reverse(reverse))));
+        }
+    }
+
+    /// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14)
+    fn extended_telemetry(&mut self, enable: [bool; N]) {
+        for _ in 0..DshotCommand::ExtendedTelemetryEnable.repeats() {
+            self.push_frames(enable.map(|enable| Some(super::extended_telemetry(enable))));
+        }
     }
 
     /// Set the throttle for each motor. All values are clamped between 48 and 2047
-    fn throttle_clamp(&mut self, throttle: [u16;4]) {
-        self.sm0.write(dshot::throttle_clamp(throttle[0], false) as u32);
-        self.sm1.write(dshot::throttle_clamp(throttle[1], false) as u32);
-        self.sm2.write(dshot::throttle_clamp(throttle[2], false) as u32);
-        self.sm3.write(dshot::throttle_clamp(throttle[3], false) as u32);
+    fn throttle_clamp(&mut self, throttle: [u16; N]) {
+        self.push_frames(throttle.map(|throttle| Some(dshot::throttle_clamp(throttle, false))));
+    }
+
+    /// Set the throttle for each motor, requesting serial telemetry from the selected motors
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16; N], telemetry: [bool; N]) {
+        self.push_frames(core::array::from_fn(|i| {
+            Some(dshot::throttle_clamp(throttle[i], telemetry[i]))
+        }));
     }
 
     /// Set the throttle for each motor to zero (DShot command 48)
     fn throttle_minimum(&mut self) {
-        self.sm0.write(dshot::throttle_minimum(false) as u32);
-        self.sm1.write(dshot::throttle_minimum(false) as u32);
-        self.sm2.write(dshot::throttle_minimum(false) as u32);
-        self.sm3.write(dshot::throttle_minimum(false) as u32);
+        self.push_frames([Some(dshot::throttle_minimum(false)); N]);
+    }
+
+    /// Parallel output does not support bidirectional DShot, so there is never any eRPM
+    fn erpm(&mut self) -> [Option<u32>; N] {
+        [None; N]
+    }
+
+    /// Parallel output does not support bidirectional DShot, so there is never any telemetry
+    fn telemetry(&mut self) -> [Option<Telemetry>; N] {
+        [None; N]
     }
 }
@@ -0,0 +1,55 @@
+//! Encoding of DShot frames.
+//!
+//! A frame carries an 11-bit value, a telemetry request bit, and a 4-bit
+//! checksum XOR-ing together the nibbles of the other 12 bits. Values 0 to 47
+//! are special commands, and 48 to 2047 throttle.
+
+/// Lowest throttle value sent on the wire
+pub const THROTTLE_MIN: u16 = 48;
+/// Highest throttle value sent on the wire
+pub const THROTTLE_MAX: u16 = 2047;
+
+/// Encode the frame of an 11-bit value, including checksum and telemetry request bit
+pub(crate) fn frame(value: u16, telemetry: bool) -> u16 {
+    let data = ((value & 0x07FF) << 1) | telemetry as u16;
+    let checksum = (data ^ (data >> 4) ^ (data >> 8)) & 0x0F;
+    (data << 4) | checksum
+}
+
+/// Encode the frame of a throttle value, clamped between 48 and 2047
+pub fn throttle_clamp(throttle: u16, telemetry: bool) -> u16 {
+    frame(throttle.clamp(THROTTLE_MIN, THROTTLE_MAX), telemetry)
+}
+
+/// Encode the frame of the lowest throttle value
+pub fn throttle_minimum(telemetry: bool) -> u16 {
+    frame(THROTTLE_MIN, telemetry)
+}
+
+/// Encode the frame of spin direction command 21 (reversed) or 20 (normal), with
+/// the telemetry request bit set, as ESCs ignore commands without it
+pub fn reverse(reverse: bool) -> u16 {
+    match reverse {
+        true => frame(21, true),
+        false => frame(20, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_checksum_and_telemetry_bit() {
+        assert_eq!(frame(1046, false), 0b1000_0010_1100_0110);
+        assert_eq!(frame(1046, true), 0b1000_0010_1101_0111);
+        assert_eq!(frame(0, false), 0);
+    }
+
+    #[test]
+    fn clamps_throttle() {
+        assert_eq!(throttle_clamp(0, false), frame(THROTTLE_MIN, false));
+        assert_eq!(throttle_clamp(u16::MAX, true), frame(THROTTLE_MAX, true));
+        assert_eq!(throttle_minimum(false), throttle_clamp(THROTTLE_MIN, false));
+    }
+}
@@ -0,0 +1,230 @@
+//! Stopping the motors when the control loop stops updating them.
+//!
+//! If the task sending throttle values hangs, the state machines simply stop
+//! sending frames, and ESCs differ in how they handle that. The failsafe is
+//! split in two halves sharing a `FailsafeLink`: the control loop records its
+//! throttle through a `FailsafeControl`, along with the time it was computed,
+//! while `Failsafe` owns the driver and `tick` is called from a timer at the
+//! DShot update rate. Each tick sends the latest throttle, and once none was
+//! recorded for longer than the timeout, the failsafe trips and sends minimum
+//! throttle until it is reset. Ticks never wait for room in the TX FIFOs, so
+//! they cannot hang on the driver either.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use super::encoder::THROTTLE_MIN;
+use super::{DshotPioTry, DshotValue};
+
+/// State shared by the two halves of a failsafe
+#[derive(Clone, Copy)]
+struct Shared<const N: usize> {
+    throttle: [u16; N],
+    /// When the throttle was recorded, or when the first tick happened if none was yet
+    recorded_us: Option<u64>,
+    tripped: bool,
+}
+
+impl<const N: usize> Shared<N> {
+    const fn new() -> Shared<N> {
+        Shared { throttle: [THROTTLE_MIN; N], recorded_us: None, tripped: false }
+    }
+}
+
+/// Connects the control loop to the failsafe, usually placed in a `static` so
+/// that both halves can live in different tasks or interrupt handlers
+pub struct FailsafeLink<const N: usize> {
+    shared: Mutex<Cell<Shared<N>>>,
+}
+
+impl<const N: usize> Default for FailsafeLink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FailsafeLink<N> {
+    pub const fn new() -> FailsafeLink<N> {
+        FailsafeLink { shared: Mutex::new(Cell::new(Shared::new())) }
+    }
+
+    /// Split into the handle for the control loop, and the failsafe owning the driver,
+    /// which trips once no throttle was recorded for `timeout_us` microseconds
+    pub fn split<D: DshotPioTry<N>>(&self, dshot: D, timeout_us: u64) -> (FailsafeControl<'_, N>, Failsafe<'_, D, N>) {
+        (FailsafeControl { link: self }, Failsafe { dshot, link: self, timeout_us })
+    }
+
+    /// Modify the shared state within a critical section
+    fn update<R>(&self, f: impl FnOnce(&mut Shared<N>) -> R) -> R {
+        critical_section::with(|cs| {
+            let cell = self.shared.borrow(cs);
+            let mut shared = cell.get();
+            let result = f(&mut shared);
+            cell.set(shared);
+            result
+        })
+    }
+}
+
+/// Control loop side of a failsafe, recording the throttle to send
+#[derive(Clone, Copy)]
+pub struct FailsafeControl<'a, const N: usize> {
+    link: &'a FailsafeLink<N>,
+}
+
+impl<const N: usize> FailsafeControl<'_, N> {
+    /// Record the throttle for each motor, computed at `now_us` microseconds, to be sent from
+    /// the next tick on. Throttle is ignored while the failsafe is tripped, so motors do not
+    /// spin up unexpectedly when the control loop recovers. Returns whether it was accepted.
+    pub fn throttle(&self, throttle: [u16; N], now_us: u64) -> bool {
+        self.link.update(|shared| {
+            if !shared.tripped {
+                shared.throttle = throttle;
+                shared.recorded_us = Some(now_us);
+            }
+            !shared.tripped
+        })
+    }
+
+    /// Whether the failsafe has tripped
+    pub fn is_tripped(&self) -> bool {
+        self.link.update(|shared| shared.tripped)
+    }
+
+    /// Accept throttle again after the failsafe has tripped. Minimum throttle is
+    /// sent until the control loop records a new one.
+    pub fn reset(&self) {
+        self.link.update(|shared| *shared = Shared::new());
+    }
+}
+
+/// Timer side of a failsafe, owning the driver, see the module documentation
+pub struct Failsafe<'a, D: DshotPioTry<N>, const N: usize> {
+    dshot: D,
+    link: &'a FailsafeLink<N>,
+    timeout_us: u64,
+}
+
+impl<D: DshotPioTry<N>, const N: usize> Failsafe<'_, D, N> {
+    /// Send the latest throttle, given the current time in microseconds. Once none was recorded
+    /// within the timeout, the failsafe trips and minimum throttle is sent instead. Should be
+    /// called regularly, from a timer independent of the control loop. Motors whose TX FIFO is
+    /// full are skipped until the next tick. Returns whether the failsafe has tripped.
+    pub fn tick(&mut self, now_us: u64) -> bool {
+        let timeout_us = self.timeout_us;
+        let (throttle, tripped) = self.link.update(|shared| {
+            // Without any throttle yet, the timeout runs from the first tick
+            let recorded_us = *shared.recorded_us.get_or_insert(now_us);
+            if now_us.saturating_sub(recorded_us) > timeout_us {
+                shared.tripped = true;
+            }
+            (shared.throttle, shared.tripped)
+        });
+
+        let throttle = match tripped {
+            true => [THROTTLE_MIN; N],
+            false => throttle,
+        };
+        self.dshot.try_command(throttle.map(DshotValue::Throttle), [false; N]);
+        tripped
+    }
+
+    /// Whether the failsafe has tripped
+    pub fn is_tripped(&self) -> bool {
+        self.link.update(|shared| shared.tripped)
+    }
+
+    /// The wrapped driver, for sending special commands and reading telemetry between ticks
+    pub fn inner(&mut self) -> &mut D {
+        &mut self.dshot
+    }
+
+    /// Give back the wrapped driver
+    pub fn into_inner(self) -> D {
+        self.dshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{throttle, MockDshot};
+
+    #[test]
+    fn sends_latest_throttle_every_tick() {
+        let link = FailsafeLink::<2>::new();
+        let (control, mut failsafe) = link.split(MockDshot::new(), 50_000);
+
+        assert!(!failsafe.tick(0));
+        assert_eq!(failsafe.inner().last(), [throttle(THROTTLE_MIN); 2]);
+
+        assert!(control.throttle([500, 600], 1_000));
+        assert!(!failsafe.tick(2_000));
+        assert!(!failsafe.tick(3_000));
+        assert_eq!(failsafe.inner().sent.len(), 3);
+        assert_eq!(failsafe.inner().last(), [throttle(500), throttle(600)]);
+    }
+
+    #[test]
+    fn trips_after_timeout() {
+        let link = FailsafeLink::<1>::new();
+        let (control, mut failsafe) = link.split(MockDshot::new(), 50_000);
+
+        control.throttle([1000], 10_000);
+        assert!(!failsafe.tick(60_000));
+        assert_eq!(failsafe.inner().last(), [throttle(1000)]);
+
+        assert!(failsafe.tick(60_001));
+        assert!(control.is_tripped());
+        assert_eq!(failsafe.inner().last(), [throttle(THROTTLE_MIN)]);
+
+        // Throttle is ignored until the failsafe is reset
+        assert!(!control.throttle([1000], 60_002));
+        assert!(failsafe.tick(60_003));
+        assert_eq!(failsafe.inner().last(), [throttle(THROTTLE_MIN)]);
+    }
+
+    #[test]
+    fn timeout_runs_from_first_tick_without_throttle() {
+        let link = FailsafeLink::<1>::new();
+        let (_control, mut failsafe) = link.split(MockDshot::new(), 50_000);
+
+        assert!(!failsafe.tick(1_000_000));
+        assert!(!failsafe.tick(1_050_000));
+        assert!(failsafe.tick(1_050_001));
+    }
+
+    #[test]
+    fn reset_accepts_throttle_again() {
+        let link = FailsafeLink::<1>::new();
+        let (control, mut failsafe) = link.split(MockDshot::new(), 50_000);
+        failsafe.tick(0);
+        assert!(failsafe.tick(50_001));
+
+        control.reset();
+        assert!(!failsafe.is_tripped());
+        assert!(!failsafe.tick(100_000));
+        assert_eq!(failsafe.inner().last(), [throttle(THROTTLE_MIN)]);
+
+        assert!(control.throttle([700], 100_000));
+        assert!(!failsafe.tick(101_000));
+        assert_eq!(failsafe.inner().last(), [throttle(700)]);
+    }
+
+    #[test]
+    fn skips_motors_with_full_fifo() {
+        let link = FailsafeLink::<2>::new();
+        let (control, mut failsafe) = link.split(MockDshot::new(), 50_000);
+        failsafe.inner().full = [true, false];
+
+        control.throttle([500, 600], 0);
+        failsafe.tick(0);
+        assert_eq!(failsafe.inner().last(), [None, throttle(600)]);
+
+        // The skipped motor is sent the throttle again on the next tick
+        failsafe.inner().full = [false, false];
+        failsafe.tick(1_000);
+        assert_eq!(failsafe.inner().last(), [throttle(500), throttle(600)]);
+    }
+}
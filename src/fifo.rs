@@ -0,0 +1,213 @@
+//! Sending frames through the FIFOs of the state machines, shared by the backends.
+//!
+//! Each backend gives access to the FIFOs of the state machine driving each
+//! motor, and the methods of `DshotPioTrait` are built on top of that, so that
+//! word `i` always ends up with motor `i`, whichever state machine drives it.
+
+use super::encoder as dshot;
+use super::telemetry::{self, Telemetry};
+use super::{DshotCommand, DshotMode, DshotValue};
+
+/// Access to the FIFOs of the state machine driving each motor of a driver
+pub(crate) trait DshotFifo {
+    /// Signalling mode of the state machines, which sets the word pushed for a frame
+    fn mode(&self) -> DshotMode;
+    /// Whether the TX FIFO of the state machine driving `motor` is full
+    fn is_full(&mut self, motor: usize) -> bool;
+    /// Push a word into the TX FIFO of the state machine driving `motor`
+    fn push(&mut self, motor: usize, word: u32);
+    /// Pull a word from the RX FIFO of the state machine driving `motor`, if any
+    fn pull(&mut self, motor: usize) -> Option<u32>;
+}
+
+/// Push word `i` into the TX FIFO of motor `i`
+pub(crate) fn dispatch<const N: usize>(fifo: &mut impl DshotFifo, words: [u32; N]) {
+    for (motor, word) in words.into_iter().enumerate() {
+        fifo.push(motor, word);
+    }
+}
+
+/// Push word `i` into the TX FIFO of motor `i`, skipping any `None`
+pub(crate) fn dispatch_some<const N: usize>(fifo: &mut impl DshotFifo, words: [Option<u32>; N]) {
+    for (motor, word) in words.into_iter().enumerate() {
+        if let Some(word) = word {
+            fifo.push(motor, word);
+        }
+    }
+}
+
+/// Send frame `i` to motor `i`
+fn dispatch_frames<const N: usize>(fifo: &mut impl DshotFifo, frames: [u16; N]) {
+    let mode = fifo.mode();
+    dispatch(fifo, frames.map(|frame| mode.tx_word(frame)));
+}
+
+/// Send frame `i` to motor `i`, `repeats` times in a row, waiting for room in the TX FIFOs
+fn repeat_frames<const N: usize>(fifo: &mut impl DshotFifo, frames: [u16; N], repeats: u8) {
+    let words = frames.map(|frame| fifo.mode().tx_word(frame));
+    for _ in 0..repeats {
+        for (motor, word) in words.into_iter().enumerate() {
+            while fifo.is_full(motor) {}
+            fifo.push(motor, word);
+        }
+    }
+}
+
+/// Send a special command or throttle value to each motor, optionally requesting telemetry
+pub(crate) fn command<const N: usize>(fifo: &mut impl DshotFifo, command: [DshotValue; N], telemetry: [bool; N]) {
+    let frames: [u16; N] = core::array::from_fn(|i| command[i].frame(telemetry[i]));
+    dispatch_frames(fifo, frames);
+}
+
+/// Send a special command to each motor, motors given `None` are left alone
+pub(crate) fn send_command<const N: usize>(fifo: &mut impl DshotFifo, command: [Option<DshotCommand>; N]) {
+    let mode = fifo.mode();
+    dispatch_some(fifo, command.map(|command| command.map(|command| mode.tx_word(command.frame()))));
+}
+
+/// Set the direction of rotation for each motor (DShot command 20 or 21),
+/// sending the command as many times as the ESCs require
+pub(crate) fn reverse<const N: usize>(fifo: &mut impl DshotFifo, reverse: [bool; N]) {
+    let repeats = DshotCommand::SpinDirectionNormal.repeats();
+    repeat_frames(fifo, reverse.map(dshot::reverse), repeats);
+}
+
+/// Enable or disable Extended DShot Telemetry for each motor (DShot command 13 and 14),
+/// sending the command as many times as the ESCs require
+pub(crate) fn extended_telemetry<const N: usize>(fifo: &mut impl DshotFifo, enable: [bool; N]) {
+    let repeats = DshotCommand::ExtendedTelemetryEnable.repeats();
+    repeat_frames(fifo, enable.map(super::extended_telemetry), repeats);
+}
+
+/// Set the throttle for each motor, clamped between 48 and 2047, requesting telemetry from the selected motors
+pub(crate) fn throttle_clamp<const N: usize>(fifo: &mut impl DshotFifo, throttle: [u16; N], telemetry: [bool; N]) {
+    let frames: [u16; N] = core::array::from_fn(|i| dshot::throttle_clamp(throttle[i], telemetry[i]));
+    dispatch_frames(fifo, frames);
+}
+
+/// Set the throttle for each motor to zero (DShot command 48)
+pub(crate) fn throttle_minimum<const N: usize>(fifo: &mut impl DshotFifo) {
+    dispatch_frames(fifo, [dshot::throttle_minimum(false); N]);
+}
+
+/// Drain the RX FIFO of `motor` and decode the most recent eRPM reply
+pub(crate) fn latest_erpm(fifo: &mut impl DshotFifo, motor: usize) -> Option<u32> {
+    let mut erpm = None;
+    while let Some(raw) = fifo.pull(motor) {
+        if let Ok(telemetry) = telemetry::decode_erpm(raw) {
+            erpm = Some(telemetry.erpm());
+        }
+    }
+    erpm
+}
+
+/// Decode the oldest valid ESC reply waiting in the RX FIFO of `motor`
+pub(crate) fn next_telemetry(fifo: &mut impl DshotFifo, motor: usize) -> Option<Telemetry> {
+    while let Some(raw) = fifo.pull(motor) {
+        if let Ok(telemetry) = telemetry::decode(raw) {
+            return Some(telemetry);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFifo;
+    use std::vec::Vec;
+
+    /// Words pushed by a call, as `(motor, word)`, for the given frames in normal mode
+    fn expected(frames: impl IntoIterator<Item = (usize, u16)>) -> Vec<(usize, u32)> {
+        frames.into_iter().map(|(motor, frame)| (motor, DshotMode::Normal.tx_word(frame))).collect()
+    }
+
+    fn check_routing<const N: usize>() {
+        let values: [DshotValue; N] = core::array::from_fn(|i| DshotValue::Throttle(100 * (i as u16 + 1)));
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        command(&mut fifo, values, [false; N]);
+        assert_eq!(fifo.pushed, expected((0..N).map(|i| (i, values[i].frame(false)))));
+
+        let beeps = [DshotCommand::Beep1, DshotCommand::Beep2, DshotCommand::Beep3, DshotCommand::Beep4];
+        let commands: [Option<DshotCommand>; N] = core::array::from_fn(|i| Some(beeps[i]));
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        send_command(&mut fifo, commands);
+        assert_eq!(fifo.pushed, expected((0..N).map(|i| (i, commands[i].unwrap().frame()))));
+
+        let throttle: [u16; N] = core::array::from_fn(|i| 500 + i as u16);
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        throttle_clamp(&mut fifo, throttle, [false; N]);
+        assert_eq!(fifo.pushed, expected((0..N).map(|i| (i, dshot::throttle_clamp(throttle[i], false)))));
+    }
+
+    #[test]
+    fn routes_word_i_to_motor_i() {
+        check_routing::<1>();
+        check_routing::<2>();
+        check_routing::<3>();
+        check_routing::<4>();
+    }
+
+    #[test]
+    fn send_command_skips_motors_given_none() {
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        send_command(&mut fifo, [None, Some(DshotCommand::Beep1), None, Some(DshotCommand::Beep2)]);
+        assert_eq!(fifo.pushed, expected([(1, DshotCommand::Beep1.frame()), (3, DshotCommand::Beep2.frame())]));
+    }
+
+    #[test]
+    fn dispatch_some_skips_none() {
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        dispatch_some(&mut fifo, [Some(10), None, None, Some(13)]);
+        assert_eq!(fifo.pushed, [(0, 10), (3, 13)]);
+
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        dispatch_some(&mut fifo, [None::<u32>; 4]);
+        assert!(fifo.pushed.is_empty());
+    }
+
+    #[test]
+    fn reverse_is_repeated() {
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        reverse(&mut fifo, [true, false, true]);
+        assert_eq!(fifo.pushed.len(), 3 * DshotCommand::SpinDirectionNormal.repeats() as usize);
+        let frames = [(0, dshot::reverse(true)), (1, dshot::reverse(false)), (2, dshot::reverse(true))];
+        assert_eq!(fifo.pushed, expected(frames.repeat(6)));
+    }
+
+    #[test]
+    fn latest_erpm_drains_replies() {
+        let mut fifo = MockFifo::new(DshotMode::Bidirectional);
+        // Stopped motor, then a period of 976 us, then an invalid reply
+        fifo.rx[1].extend([0x5_2951, 0xE_D273, 0]);
+        assert_eq!(latest_erpm(&mut fifo, 1), Some(60_000_000 / 976));
+        assert!(fifo.rx[1].is_empty());
+        assert_eq!(latest_erpm(&mut fifo, 0), None);
+    }
+
+    #[test]
+    fn next_telemetry_skips_invalid_replies() {
+        let mut fifo = MockFifo::new(DshotMode::Bidirectional);
+        fifo.rx[2].extend([0, 0xE_D273, 0x5_2951]);
+        assert_eq!(next_telemetry(&mut fifo, 2), telemetry::decode(0xE_D273).ok());
+        assert_eq!(fifo.rx[2].len(), 1);
+        assert_eq!(next_telemetry(&mut fifo, 3), None);
+    }
+
+    #[test]
+    fn extended_telemetry_is_repeated() {
+        let mut fifo = MockFifo::new(DshotMode::Normal);
+        extended_telemetry(&mut fifo, [true, false]);
+        let enable = DshotCommand::ExtendedTelemetryEnable.frame();
+        let disable = DshotCommand::ExtendedTelemetryDisable.frame();
+        assert_eq!(fifo.pushed, expected([(0, enable), (1, disable)].repeat(6)));
+    }
+
+    #[test]
+    fn words_follow_the_mode() {
+        let mut fifo = MockFifo::new(DshotMode::Bidirectional);
+        throttle_minimum::<2>(&mut fifo);
+        let word = DshotMode::Bidirectional.tx_word(dshot::throttle_minimum(false));
+        assert_eq!(fifo.pushed, [(0, word), (1, word)]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Driving more motors than a single PIO block has state machines for.
+//!
+//! A group combines two drivers, of `A` and `B` motors, into a single driver of
+//! `M = A + B` motors. Motors `0..A` are those of the first driver, and motors
+//! `A..M` those of the second one. As a group is a driver itself, groups can be
+//! nested to combine more than two PIO blocks.
+
+use super::telemetry::Telemetry;
+use super::{DshotCommand, DshotPioAsync, DshotPioTrait, DshotPioTry, DshotValue};
+
+/// Drivers whose state machines can be started in phase with those of other drivers
+pub trait DshotSync {
+    /// Stop the state machines, drop any frames not yet sent, and move them back to the start of the program.
+    /// Repeating state machines send all-zero frames again once started, until the next frame is pushed.
+    fn halt(&mut self);
+    /// Enable the halted state machines and restart their clock dividers
+    fn start(&mut self);
+}
+
+/// Drivers which can tell where each of their motors is driven from
+pub trait DshotLocate {
+    /// Index of the PIO block, and of the state machine within it, driving a motor
+    fn locate(&self, motor: usize) -> (usize, usize);
+}
+
+/// Two drivers acting as one, see the module documentation
+pub struct DshotPioGroup<const A: usize, const B: usize, const M: usize, First, Second> {
+    first: First,
+    second: Second,
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotPioTrait<A>,
+    Second: DshotPioTrait<B>,
+{
+    /// The motor count of the group must be the sum of those of its drivers
+    const VALID_M: () = assert!(A + B == M, "DshotPioGroup motor count must be the sum of its drivers' motor counts");
+
+    /// Combine two drivers. Their frames are only in phase with each other after `resync`.
+    pub fn new(first: First, second: Second) -> Self {
+        let () = Self::VALID_M;
+        DshotPioGroup { first, second }
+    }
+
+    /// Driver of motors `0..A`
+    pub fn first(&mut self) -> &mut First {
+        &mut self.first
+    }
+
+    /// Driver of motors `A..M`
+    pub fn second(&mut self) -> &mut Second {
+        &mut self.second
+    }
+
+    /// Give back the two drivers
+    pub fn into_inner(self) -> (First, Second) {
+        (self.first, self.second)
+    }
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotPioTrait<A> + DshotSync,
+    Second: DshotPioTrait<B> + DshotSync,
+{
+    /// Restart the state machines of both drivers so that all motors' frames start together.
+    /// Any frames not yet sent are dropped.
+    ///
+    /// Each PIO block is started by its own write to its CTRL register, one right after the
+    /// other within a critical section, so blocks start a few system clock cycles apart. That
+    /// is well within a single PIO cycle at DShot speeds. The RP2350 could start neighbouring
+    /// blocks with a single write, through the NEXT_PIO and PREV_PIO masks of CTRL, but this
+    /// is not used, so the skew is the same as on the RP2040.
+    pub fn resync(&mut self) {
+        self.halt();
+        self.start();
+    }
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotSync for DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotSync,
+    Second: DshotSync,
+{
+    fn halt(&mut self) {
+        self.first.halt();
+        self.second.halt();
+    }
+
+    fn start(&mut self) {
+        // Nothing may run between the writes starting each block
+        critical_section::with(|_| {
+            self.first.start();
+            self.second.start();
+        });
+    }
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotLocate for DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotLocate,
+    Second: DshotLocate,
+{
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        assert!(motor < M, "Motor index out of range");
+        match motor < A {
+            true => self.first.locate(motor),
+            false => self.second.locate(motor - A),
+        }
+    }
+}
+
+/// Split the values of all motors into those of each driver
+fn split<T: Copy, const A: usize, const B: usize, const M: usize>(values: [T; M]) -> ([T; A], [T; B]) {
+    (core::array::from_fn(|i| values[i]), core::array::from_fn(|i| values[A + i]))
+}
+
+/// Join the values of each driver into those of all motors
+fn join<T: Copy, const A: usize, const B: usize, const M: usize>(first: [T; A], second: [T; B]) -> [T; M] {
+    core::array::from_fn(|i| match i < A {
+        true => first[i],
+        false => second[i - A],
+    })
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotPioTrait<M> for DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotPioTrait<A>,
+    Second: DshotPioTrait<B>,
+{
+    fn command(&mut self, command: [DshotValue; M], telemetry: [bool; M]) {
+        let (command0, command1) = split(command);
+        let (telemetry0, telemetry1) = split(telemetry);
+        self.first.command(command0, telemetry0);
+        self.second.command(command1, telemetry1);
+    }
+
+    fn send_command(&mut self, command: [Option<DshotCommand>; M]) {
+        let (command0, command1) = split(command);
+        self.first.send_command(command0);
+        self.second.send_command(command1);
+    }
+
+    fn reverse(&mut self, reverse: [bool; M]) {
+        let (reverse0, reverse1) = split(reverse);
+        self.first.reverse(reverse0);
+        self.second.reverse(reverse1);
+    }
+
+    fn extended_telemetry(&mut self, enable: [bool; M]) {
+        let (enable0, enable1) = split(enable);
+        self.first.extended_telemetry(enable0);
+        self.second.extended_telemetry(enable1);
+    }
+
+    fn throttle_clamp(&mut self, throttle: [u16; M]) {
+        let (throttle0, throttle1) = split(throttle);
+        self.first.throttle_clamp(throttle0);
+        self.second.throttle_clamp(throttle1);
+    }
+
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16; M], telemetry: [bool; M]) {
+        let (throttle0, throttle1) = split(throttle);
+        let (telemetry0, telemetry1) = split(telemetry);
+        self.first.throttle_clamp_telemetry(throttle0, telemetry0);
+        self.second.throttle_clamp_telemetry(throttle1, telemetry1);
+    }
+
+    fn throttle_minimum(&mut self) {
+        self.first.throttle_minimum();
+        self.second.throttle_minimum();
+    }
+
+    fn erpm(&mut self) -> [Option<u32>; M] {
+        join(self.first.erpm(), self.second.erpm())
+    }
+
+    fn telemetry(&mut self) -> [Option<Telemetry>; M] {
+        join(self.first.telemetry(), self.second.telemetry())
+    }
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotPioAsync<M> for DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotPioAsync<A>,
+    Second: DshotPioAsync<B>,
+{
+    async fn command_async(&mut self, command: [DshotValue; M], telemetry: [bool; M]) {
+        let (command0, command1) = split(command);
+        let (telemetry0, telemetry1) = split(telemetry);
+        self.first.command_async(command0, telemetry0).await;
+        self.second.command_async(command1, telemetry1).await;
+    }
+
+    async fn send_command_async(&mut self, command: [Option<DshotCommand>; M]) {
+        let (command0, command1) = split(command);
+        self.first.send_command_async(command0).await;
+        self.second.send_command_async(command1).await;
+    }
+
+    async fn reverse_async(&mut self, reverse: [bool; M]) {
+        let (reverse0, reverse1) = split(reverse);
+        self.first.reverse_async(reverse0).await;
+        self.second.reverse_async(reverse1).await;
+    }
+
+    async fn extended_telemetry_async(&mut self, enable: [bool; M]) {
+        let (enable0, enable1) = split(enable);
+        self.first.extended_telemetry_async(enable0).await;
+        self.second.extended_telemetry_async(enable1).await;
+    }
+
+    async fn throttle_clamp_async(&mut self, throttle: [u16; M]) {
+        let (throttle0, throttle1) = split(throttle);
+        self.first.throttle_clamp_async(throttle0).await;
+        self.second.throttle_clamp_async(throttle1).await;
+    }
+
+    async fn throttle_clamp_telemetry_async(&mut self, throttle: [u16; M], telemetry: [bool; M]) {
+        let (throttle0, throttle1) = split(throttle);
+        let (telemetry0, telemetry1) = split(telemetry);
+        self.first.throttle_clamp_telemetry_async(throttle0, telemetry0).await;
+        self.second.throttle_clamp_telemetry_async(throttle1, telemetry1).await;
+    }
+
+    async fn throttle_minimum_async(&mut self) {
+        self.first.throttle_minimum_async().await;
+        self.second.throttle_minimum_async().await;
+    }
+}
+
+impl<const A: usize, const B: usize, const M: usize, First, Second> DshotPioTry<M> for DshotPioGroup<A, B, M, First, Second>
+where
+    First: DshotPioTry<A>,
+    Second: DshotPioTry<B>,
+{
+    fn try_command(&mut self, command: [DshotValue; M], telemetry: [bool; M]) -> [bool; M] {
+        let (command0, command1) = split(command);
+        let (telemetry0, telemetry1) = split(telemetry);
+        join(self.first.try_command(command0, telemetry0), self.second.try_command(command1, telemetry1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDshot, SyncLog};
+    use crate::telemetry;
+
+    /// Value and telemetry bit sent to each motor
+    fn sent<const N: usize>(values: [DshotValue; N], telemetry: [bool; N]) -> [Option<(DshotValue, bool)>; N] {
+        core::array::from_fn(|i| Some((values[i], telemetry[i])))
+    }
+
+    #[test]
+    fn splits_values_between_drivers() {
+        let mut group = DshotPioGroup::<2, 3, 5, _, _>::new(MockDshot::<2>::new(), MockDshot::<3>::new());
+        let values = [100, 200, 300, 400, 500].map(DshotValue::Throttle);
+        group.command(values, [true, false, false, true, false]);
+        assert_eq!(group.first().last(), sent([values[0], values[1]], [true, false]));
+        assert_eq!(group.second().last(), sent([values[2], values[3], values[4]], [false, true, false]));
+
+        group.throttle_clamp([1000, 1100, 1200, 1300, 1400]);
+        assert_eq!(group.first().last(), sent([1000, 1100].map(DshotValue::Throttle), [false; 2]));
+        assert_eq!(group.second().last(), sent([1200, 1300, 1400].map(DshotValue::Throttle), [false; 3]));
+    }
+
+    #[test]
+    fn joins_replies_in_motor_order() {
+        let mut group = DshotPioGroup::<2, 3, 5, _, _>::new(MockDshot::<2>::new(), MockDshot::<3>::new());
+        group.first().erpm = [Some(1), None];
+        group.second().erpm = [Some(3), Some(4), None];
+        assert_eq!(group.erpm(), [Some(1), None, Some(3), Some(4), None]);
+
+        let reply = telemetry::decode(0xE_D273).ok();
+        group.second().telemetry = [None, None, reply];
+        assert_eq!(group.telemetry(), [None, None, None, None, reply]);
+
+        group.second().full = [false, true, false];
+        assert_eq!(group.try_command([DshotValue::Throttle(100); 5], [false; 5]), [false, false, false, true, false]);
+    }
+
+    #[test]
+    fn resync_halts_both_drivers_before_starting_either() {
+        let log = SyncLog::default();
+        let first = MockDshot::<2>::new().on_block(0).logging_to(&log);
+        let second = MockDshot::<2>::new().on_block(1).logging_to(&log);
+        let mut group = DshotPioGroup::<2, 2, 4, _, _>::new(first, second);
+
+        group.resync();
+        assert_eq!(*log.borrow(), [(0, false), (1, false), (0, true), (1, true)]);
+        assert_eq!(group.first().restarts, 1);
+        assert_eq!(group.second().restarts, 1);
+    }
+
+    #[test]
+    fn locates_motors_of_nested_groups() {
+        let front = MockDshot::<4>::new().on_block(0);
+        let rear = MockDshot::<4>::new().on_block(1);
+        let tail = MockDshot::<2>::new().on_block(2);
+        let group = DshotPioGroup::<8, 2, 10, _, _>::new(DshotPioGroup::<4, 4, 8, _, _>::new(front, rear), tail);
+
+        assert_eq!(group.locate(0), (0, 0));
+        assert_eq!(group.locate(3), (0, 3));
+        assert_eq!(group.locate(4), (1, 0));
+        assert_eq!(group.locate(7), (1, 3));
+        assert_eq!(group.locate(8), (2, 0));
+        assert_eq!(group.locate(9), (2, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Motor index out of range")]
+    fn rejects_motors_beyond_the_group() {
+        let group = DshotPioGroup::<1, 1, 2, _, _>::new(MockDshot::<1>::new(), MockDshot::<1>::new());
+        group.locate(2);
+    }
+}
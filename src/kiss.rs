@@ -0,0 +1,337 @@
+//! KISS / BLHeli_32 serial telemetry.
+//!
+//! ESCs supporting this reply on a separate UART line with a 10-byte packet,
+//! whenever they receive a DShot frame with the telemetry request bit set. As
+//! all ESCs typically share the same line, only one motor is asked at a time,
+//! and the next one only once the reply has been read, or has timed out.
+//!
+//! Reading never blocks: only the bytes already received are taken from the
+//! UART, and packets are assembled across calls. Bytes are dropped until the
+//! last 10 received make up a packet with a valid checksum, so that a lost or
+//! corrupted byte does not shift every packet after it.
+
+use embedded_io::{Read, ReadReady};
+
+use super::DshotPioTrait;
+
+/// Length of a KISS telemetry packet, including the checksum
+pub const PACKET_LEN: usize = 10;
+
+/// Errors that may occur when reading a KISS telemetry packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KissError<E> {
+    /// The underlying reader failed
+    Read(E),
+    /// The motor asked for telemetry did not reply within the timeout
+    Timeout(usize),
+    /// No motor was waiting to reply, as nothing was requested since the last packet was read
+    NotRequested,
+}
+
+impl<E> From<E> for KissError<E> {
+    fn from(error: E) -> Self {
+        KissError::Read(error)
+    }
+}
+
+/// Telemetry packet reported by an ESC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KissTelemetryFrame {
+    /// Temperature in degrees Celsius
+    pub temperature: u8,
+    /// Voltage in units of 10 mV
+    pub voltage: u16,
+    /// Current in units of 10 mA
+    pub current: u16,
+    /// Consumed charge in mAh
+    pub consumption: u16,
+    /// Electrical revolutions per minute
+    pub erpm: u32,
+}
+
+impl KissTelemetryFrame {
+    /// Parse a raw packet, returning `None` if the checksum does not match
+    pub fn from_bytes(bytes: &[u8; PACKET_LEN]) -> Option<KissTelemetryFrame> {
+        if crc8(&bytes[..PACKET_LEN - 1]) != bytes[PACKET_LEN - 1] {
+            return None;
+        }
+
+        Some(KissTelemetryFrame {
+            temperature: bytes[0],
+            voltage: u16::from_be_bytes([bytes[1], bytes[2]]),
+            current: u16::from_be_bytes([bytes[3], bytes[4]]),
+            consumption: u16::from_be_bytes([bytes[5], bytes[6]]),
+            erpm: u16::from_be_bytes([bytes[7], bytes[8]]) as u32 * 100,
+        })
+    }
+}
+
+/// CRC8 with polynomial 0x07, as used by KISS telemetry
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x07,
+        })
+    })
+}
+
+/// Requests serial telemetry from N motors in turn, and reads back the replies
+pub struct KissTelemetry<R, const N: usize> {
+    reader: R,
+    timeout_us: u64,
+    next: usize,
+    pending: Option<(usize, u64)>,
+    /// Bytes of the packet received so far
+    packet: [u8; PACKET_LEN],
+    received: usize,
+}
+
+impl<R: Read + ReadReady, const N: usize> KissTelemetry<R, N> {
+    /// Read replies from `reader`, giving up on a motor which has not replied
+    /// within `timeout_us` microseconds of being asked for telemetry
+    pub fn new(reader: R, timeout_us: u64) -> KissTelemetry<R, N> {
+        KissTelemetry { reader, timeout_us, next: 0, pending: None, packet: [0; PACKET_LEN], received: 0 }
+    }
+
+    /// Set the throttle for each motor, given the current time in microseconds, requesting
+    /// telemetry from the next motor in turn, unless the reply of the last motor asked has
+    /// neither been read nor timed out yet. Anything received before asking, such as a reply
+    /// arriving after its timeout, is dropped, and no motor is asked if the reader fails.
+    /// Returns the index of the motor asked, if any.
+    pub fn throttle_clamp(&mut self, dshot: &mut impl DshotPioTrait<N>, throttle: [u16; N], now_us: u64) -> Option<usize> {
+        let request = match self.pending {
+            Some((_, asked_us)) if now_us.saturating_sub(asked_us) < self.timeout_us => None,
+            _ => Some(self.next).filter(|_| self.discard().is_ok()),
+        };
+
+        let mut telemetry = [false; N];
+        if let Some(motor) = request {
+            telemetry[motor] = true;
+            self.pending = Some((motor, now_us));
+            self.next = (motor + 1) % N;
+        }
+        dshot.throttle_clamp_telemetry(throttle, telemetry);
+        request
+    }
+
+    /// Motor asked for telemetry whose reply has not been read yet, if any
+    pub fn pending(&self) -> Option<usize> {
+        self.pending.map(|(motor, _)| motor)
+    }
+
+    /// Take the bytes received so far, given the current time in microseconds, without blocking.
+    /// Returns a packet with a valid checksum along with the index of the motor which was asked
+    /// for it, or `None` if it has not been fully received yet. Once the motor has not replied
+    /// within the timeout, fails with `Timeout`, dropping anything received. The next motor
+    /// may be asked once a packet was returned, or on a timeout.
+    pub fn read(&mut self, now_us: u64) -> Result<Option<(usize, KissTelemetryFrame)>, KissError<R::Error>> {
+        let Some((motor, asked_us)) = self.pending else {
+            self.discard()?;
+            return Err(KissError::NotRequested);
+        };
+        if now_us.saturating_sub(asked_us) >= self.timeout_us {
+            self.pending = None;
+            self.discard()?;
+            return Err(KissError::Timeout(motor));
+        }
+
+        while self.reader.read_ready()? {
+            let read = self.reader.read(&mut self.packet[self.received..])?;
+            if read == 0 {
+                break;
+            }
+            self.received += read;
+
+            // Slide the window over the received bytes until the checksum matches
+            while self.received == PACKET_LEN {
+                if let Some(frame) = KissTelemetryFrame::from_bytes(&self.packet) {
+                    self.pending = None;
+                    self.received = 0;
+                    return Ok(Some((motor, frame)));
+                }
+                self.packet.copy_within(1.., 0);
+                self.received -= 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop the bytes received so far, and any waiting in the reader
+    fn discard(&mut self) -> Result<(), R::Error> {
+        self.received = 0;
+        while self.reader.read_ready()? {
+            if self.reader.read(&mut self.packet)? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get back the underlying reader
+    pub fn release(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDshot;
+    use crate::DshotValue;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Packet of 36 C, 16.80 V, 12.34 A, 567 mAh and 12300 eRPM
+    const PACKET: [u8; PACKET_LEN] = [36, 0x06, 0x90, 0x04, 0xD2, 0x02, 0x37, 0x00, 0x7B, 0x6D];
+
+    /// Motor whose telemetry bit was set by the last frame sent, if any
+    fn asked<const N: usize>(dshot: &MockDshot<N>) -> Option<usize> {
+        dshot.last().iter().position(|sent| matches!(sent, Some((DshotValue::Throttle(_), true))))
+    }
+
+    #[test]
+    fn crc8_matches_check_value() {
+        // CRC-8 with polynomial 0x07 and zero initial value
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+        assert_eq!(crc8(&[0x01]), 0x07);
+    }
+
+    #[test]
+    fn parses_known_packet() {
+        let frame = KissTelemetryFrame::from_bytes(&PACKET).unwrap();
+        assert_eq!(
+            frame,
+            KissTelemetryFrame { temperature: 36, voltage: 1680, current: 1234, consumption: 567, erpm: 12300 }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut packet = PACKET;
+        packet[PACKET_LEN - 1] ^= 0x01;
+        assert_eq!(KissTelemetryFrame::from_bytes(&packet), None);
+    }
+
+    #[test]
+    fn asks_next_motor_only_once_read() {
+        let uart = MockUart::default();
+        let mut telemetry = KissTelemetry::<_, 3>::new(uart.clone(), 1_000);
+        let mut dshot = MockDshot::new();
+
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 3], 0), Some(0));
+        assert_eq!(asked(&dshot), Some(0));
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 3], 100), None);
+        assert_eq!(asked(&dshot), None);
+
+        uart.receive(&PACKET);
+        assert_eq!(telemetry.read(150).map(|reply| reply.map(|(motor, _)| motor)), Ok(Some(0)));
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 3], 200), Some(1));
+        assert_eq!(asked(&dshot), Some(1));
+        uart.receive(&PACKET);
+        assert_eq!(telemetry.read(250).map(|reply| reply.map(|(motor, _)| motor)), Ok(Some(1)));
+    }
+
+    #[test]
+    fn asks_next_motor_on_timeout() {
+        let mut telemetry = KissTelemetry::<_, 2>::new(MockUart::default(), 1_000);
+        let mut dshot = MockDshot::new();
+
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 2], 0), Some(0));
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 2], 999), None);
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 2], 1_000), Some(1));
+        assert_eq!(telemetry.pending(), Some(1));
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 2], 2_000), Some(0));
+    }
+
+    #[test]
+    fn read_needs_a_request() {
+        let uart = MockUart::default();
+        uart.receive(&PACKET);
+        let mut telemetry = KissTelemetry::<_, 2>::new(uart.clone(), 1_000);
+        assert_eq!(telemetry.read(0), Err(KissError::NotRequested));
+        assert!(uart.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn read_assembles_packet_across_calls() {
+        let uart = MockUart::default();
+        let mut telemetry = KissTelemetry::<_, 2>::new(uart.clone(), 1_000);
+        let mut dshot = MockDshot::new();
+        telemetry.throttle_clamp(&mut dshot, [100; 2], 0);
+
+        assert_eq!(telemetry.read(100), Ok(None));
+        uart.receive(&PACKET[..4]);
+        assert_eq!(telemetry.read(200), Ok(None));
+        uart.receive(&PACKET[4..]);
+        let frame = KissTelemetryFrame::from_bytes(&PACKET).unwrap();
+        assert_eq!(telemetry.read(300), Ok(Some((0, frame))));
+        assert_eq!(telemetry.pending(), None);
+    }
+
+    #[test]
+    fn read_resyncs_on_valid_checksum() {
+        let uart = MockUart::default();
+        let mut telemetry = KissTelemetry::<_, 2>::new(uart.clone(), 1_000);
+        let mut dshot = MockDshot::new();
+        telemetry.throttle_clamp(&mut dshot, [100; 2], 0);
+
+        // Tail of a packet which was cut short, then a full packet
+        uart.receive(&PACKET[7..]);
+        uart.receive(&PACKET);
+        let frame = KissTelemetryFrame::from_bytes(&PACKET).unwrap();
+        assert_eq!(telemetry.read(100), Ok(Some((0, frame))));
+    }
+
+    #[test]
+    fn drops_replies_after_timeout() {
+        let uart = MockUart::default();
+        let mut telemetry = KissTelemetry::<_, 2>::new(uart.clone(), 1_000);
+        let mut dshot = MockDshot::new();
+        telemetry.throttle_clamp(&mut dshot, [100; 2], 0);
+
+        // The deadline is checked before reading anything
+        uart.receive(&PACKET);
+        assert_eq!(telemetry.read(1_000), Err(KissError::Timeout(0)));
+        assert!(uart.0.borrow().is_empty());
+
+        // A late reply from motor 0 is not taken for the reply of motor 1
+        uart.receive(&PACKET);
+        assert_eq!(telemetry.throttle_clamp(&mut dshot, [100; 2], 1_000), Some(1));
+        assert_eq!(telemetry.read(1_100), Ok(None));
+    }
+
+    /// UART receiving the bytes given to `receive`, shared with the test through clones
+    #[derive(Clone, Default)]
+    struct MockUart(Rc<RefCell<VecDeque<u8>>>);
+
+    impl MockUart {
+        fn receive(&self, bytes: &[u8]) {
+            self.0.borrow_mut().extend(bytes);
+        }
+    }
+
+    impl embedded_io::ErrorType for MockUart {
+        type Error = Infallible;
+    }
+
+    impl Read for MockUart {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let mut rx = self.0.borrow_mut();
+            let len = buf.len().min(rx.len());
+            for (byte, received) in buf.iter_mut().zip(rx.drain(..len)) {
+                *byte = received;
+            }
+            Ok(len)
+        }
+    }
+
+    impl ReadReady for MockUart {
+        fn read_ready(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.0.borrow().is_empty())
+        }
+    }
+}
@@ -1,14 +1,361 @@
 #![no_std]
 
-#[cfg(feature = "embassy-rp")]
+#[cfg(all(feature = "rp2040-hal", feature = "rp235x-hal"))]
+compile_error!("The rp2040-hal and rp235x-hal features cannot be enabled at the same time");
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(feature = "rp2040-hal")]
+use rp2040_hal as hal;
+
+#[cfg(feature = "rp235x-hal")]
+use rp235x_hal as hal;
+
+#[cfg(feature = "_embassy")]
 pub mod dshot_embassy_rp;
 
 #[cfg(feature = "rp2040-hal")]
 pub mod dshot_rp2040_hal;
 
+// rp235x-hal mirrors the API of rp2040-hal, so the same driver is used for both
+#[cfg(feature = "rp235x-hal")]
+#[path = "dshot_rp2040_hal.rs"]
+pub mod dshot_rp235x_hal;
+
+pub mod arming;
+pub mod command;
+pub mod encoder;
+pub mod failsafe;
+#[cfg(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal", test))]
+mod fifo;
+pub mod group;
+pub mod kiss;
+pub mod parallel;
+pub mod range;
+pub mod sequencer;
+pub mod slew;
+pub mod telemetry;
+
+#[cfg(test)]
+mod mock;
+
+pub use command::DshotCommand;
+pub use group::{DshotLocate, DshotPioGroup, DshotSync};
+use telemetry::Telemetry;
+
 pub trait DshotPioTrait<const N: usize> {
-    fn command(&mut self, command: [u16;N]);
+    fn command(&mut self, command: [DshotValue;N], telemetry: [bool;N]);
+    fn send_command(&mut self, command: [Option<DshotCommand>;N]);
     fn reverse(&mut self, reverse: [bool;N]);
+    fn extended_telemetry(&mut self, enable: [bool;N]);
     fn throttle_clamp(&mut self, throttle: [u16;N]);
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16;N], telemetry: [bool;N]);
     fn throttle_minimum(&mut self);
-}
\ No newline at end of file
+    fn erpm(&mut self) -> [Option<u32>;N];
+    fn telemetry(&mut self) -> [Option<Telemetry>;N];
+}
+
+/// Async counterpart of `DshotPioTrait`, which waits for room in the TX FIFOs instead of blocking
+#[allow(async_fn_in_trait)]
+pub trait DshotPioAsync<const N: usize> {
+    async fn command_async(&mut self, command: [DshotValue;N], telemetry: [bool;N]);
+    async fn send_command_async(&mut self, command: [Option<DshotCommand>;N]);
+    async fn reverse_async(&mut self, reverse: [bool;N]);
+    async fn extended_telemetry_async(&mut self, enable: [bool;N]);
+    async fn throttle_clamp_async(&mut self, throttle: [u16;N]);
+    async fn throttle_clamp_telemetry_async(&mut self, throttle: [u16;N], telemetry: [bool;N]);
+    async fn throttle_minimum_async(&mut self);
+}
+
+/// Drivers which can send frames without waiting for room in the TX FIFOs
+pub trait DshotPioTry<const N: usize> {
+    /// Send a special command or throttle value to each motor without blocking.
+    /// Returns which motors had their frame dropped because a TX FIFO was full.
+    fn try_command(&mut self, command: [DshotValue;N], telemetry: [bool;N]) -> [bool;N];
+}
+
+/// Value carried by a single DShot frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DshotValue {
+    /// Special command, in the range 0 to 47, always sent with the telemetry request bit set
+    Command(u8),
+    /// Throttle, clamped between 48 and 2047
+    Throttle(u16),
+}
+
+impl DshotValue {
+    /// Encode the complete frame, including checksum and telemetry request bit.
+    /// ESCs ignore commands without the telemetry bit, so it is always set for them.
+    #[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+    pub(crate) fn frame(self, telemetry: bool) -> u16 {
+        match self {
+            DshotValue::Command(command) => command_frame(command),
+            DshotValue::Throttle(throttle) => encoder::throttle_clamp(throttle, telemetry),
+        }
+    }
+}
+
+/// Encode the frame of a special command, clamped to 47, with the telemetry request bit set
+pub(crate) fn command_frame(command: u8) -> u16 {
+    encoder::frame(command.min(47) as u16, true)
+}
+
+/// Frame for enabling or disabling Extended DShot Telemetry (DShot command 13 or 14)
+#[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+pub(crate) fn extended_telemetry(enable: bool) -> u16 {
+    match enable {
+        true => DshotCommand::ExtendedTelemetryEnable.frame(),
+        false => DshotCommand::ExtendedTelemetryDisable.frame(),
+    }
+}
+
+/// Errors that may occur when setting up or sending DShot frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DshotError {
+    /// The TX FIFO of the given motor was full, so no frame was sent to any motor
+    FifoFull { motor: usize },
+    /// The DShot speed cannot be reached from the system clock within tolerance
+    UnreachableSpeed,
+    /// Throttle was given to motors that are not armed, see `arming`
+    Disarmed,
+    /// The frame rate leaves too short a gap between frames at the DShot speed, or too long a one
+    UnreachableFrameRate,
+}
+
+/// Standard DShot bit rates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DshotSpeed {
+    Dshot150,
+    Dshot300,
+    Dshot600,
+    Dshot1200,
+}
+
+/// Largest deviation of a bit period from the nominal one, in parts per thousand
+const SPEED_TOLERANCE_PPT: u64 = 20;
+
+impl DshotSpeed {
+    /// Bit rate in kbit/s
+    pub fn kbps(self) -> u32 {
+        match self {
+            DshotSpeed::Dshot150 => 150,
+            DshotSpeed::Dshot300 => 300,
+            DshotSpeed::Dshot600 => 600,
+            DshotSpeed::Dshot1200 => 1200,
+        }
+    }
+
+    /// Compute the PIO clock divider for this speed, given the system clock in Hz. The
+    /// divider is returned as its integer part and its fraction in units of 1/256.
+    pub fn clock_divider(self, sys_clk_hz: u32, mode: DshotMode) -> Result<(u16, u8), DshotError> {
+        self.divider(sys_clk_hz, mode.cycles_per_bit())
+    }
+
+    /// Compute the PIO clock divider of `DshotPioParallel`, whose program uses
+    /// 16 PIO cycles per bit, in the same format as `clock_divider`
+    pub fn parallel_clock_divider(self, sys_clk_hz: u32) -> Result<(u16, u8), DshotError> {
+        self.divider(sys_clk_hz, parallel::CYCLES_PER_BIT)
+    }
+
+    fn divider(self, sys_clk_hz: u32, cycles_per_bit: u32) -> Result<(u16, u8), DshotError> {
+        let cycles = cycles_per_bit as u64;
+        let cycle_rate = self.kbps() as u64 * 1000 * cycles;
+
+        // Divider in units of 1/256, rounded to nearest
+        let divider = (sys_clk_hz as u64 * 256 + cycle_rate / 2) / cycle_rate;
+        if !(0x100..=0xFF_FFFF).contains(&divider) {
+            return Err(DshotError::UnreachableSpeed);
+        }
+
+        // Error of the average bit rate, plus the jitter of a fractional divider,
+        // which stretches individual PIO cycles by up to one system clock
+        let actual_rate = sys_clk_hz as u64 * 256 / divider;
+        let rate_error = actual_rate.abs_diff(cycle_rate) * 1000 / cycle_rate;
+        let jitter = match divider & 0xFF {
+            0 => 0,
+            _ => 256 * 1000 / (divider * cycles),
+        };
+        if rate_error + jitter > SPEED_TOLERANCE_PPT {
+            return Err(DshotError::UnreachableSpeed);
+        }
+
+        Ok(((divider >> 8) as u16, divider as u8))
+    }
+}
+
+/// Signalling mode of the DShot state machines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DshotMode {
+    /// Regular transmit-only DShot
+    Normal,
+    /// Inverted DShot, where the ESC replies with eRPM telemetry after every frame
+    Bidirectional,
+    /// Regular DShot, where the state machines keep resending the last frame on their own
+    /// until a new one is pushed. Frames are `16 + gap_bits` bit periods apart, see `repeat`.
+    ///
+    /// Special commands are repeated as well, for as long as no other frame follows them.
+    /// Commands acting on every frame received keep acting, such as `SaveSettings` writing
+    /// the flash of the ESC over and over, so follow each command with motor stop or throttle.
+    Repeat { gap_bits: u16 },
+}
+
+/// Shortest gap between repeated frames, in bit periods
+const REPEAT_MIN_GAP_BITS: u16 = 2;
+
+/// Number of polling iterations (2 PIO cycles each) the bidirectional
+/// program waits for the ESC to start its reply before giving up.
+const BIDIR_REPLY_TIMEOUT: u16 = 400;
+
+impl DshotMode {
+    /// Repeating mode sending the given number of frames per second at the given speed. Fails
+    /// with `UnreachableFrameRate` if the rate is zero, or leaves less than two bit periods
+    /// between frames, or more than fit the gap counter.
+    pub fn repeat(speed: DshotSpeed, frame_rate_hz: u32) -> Result<DshotMode, DshotError> {
+        let frame_bits = (speed.kbps() * 1000).checked_div(frame_rate_hz).ok_or(DshotError::UnreachableFrameRate)?;
+        match frame_bits.checked_sub(16).map(u16::try_from) {
+            Some(Ok(gap_bits)) if gap_bits >= REPEAT_MIN_GAP_BITS => Ok(DshotMode::Repeat { gap_bits }),
+            _ => Err(DshotError::UnreachableFrameRate),
+        }
+    }
+
+    /// Number of PIO cycles used to transmit a single bit
+    pub(crate) fn cycles_per_bit(self) -> u32 {
+        match self {
+            DshotMode::Normal | DshotMode::Repeat { .. } => 8,
+            DshotMode::Bidirectional => 10,
+        }
+    }
+
+    /// Turn an encoded DShot frame into the word expected by the PIO program
+    #[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+    pub(crate) fn tx_word(self, frame: u16) -> u32 {
+        match self {
+            DshotMode::Normal => frame as u32,
+            // Bidirectional frames carry an inverted checksum, and the
+            // lower half-word tells the program how long to wait for a reply
+            DshotMode::Bidirectional => (((frame ^ 0x000F) as u32) << 16) | BIDIR_REPLY_TIMEOUT as u32,
+            // The upper half-word sets the length of the blanking loop, which
+            // takes `gap_bits` bit periods along with the instructions around it
+            DshotMode::Repeat { gap_bits } => {
+                (((gap_bits.max(REPEAT_MIN_GAP_BITS) - REPEAT_MIN_GAP_BITS) as u32) << 16) | frame as u32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEEDS: [DshotSpeed; 4] = [DshotSpeed::Dshot150, DshotSpeed::Dshot300, DshotSpeed::Dshot600, DshotSpeed::Dshot1200];
+
+    #[test]
+    fn repeat_gap_fills_frame_period() {
+        // 600 kbit/s at 8 kHz leaves 75 bit periods per frame, 16 of which carry the frame
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot600, 8_000), Ok(DshotMode::Repeat { gap_bits: 59 }));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot150, 1_000), Ok(DshotMode::Repeat { gap_bits: 134 }));
+        // Partial bit periods are dropped, so the rate rounds up
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot300, 7_000), Ok(DshotMode::Repeat { gap_bits: 26 }));
+    }
+
+    #[test]
+    fn repeat_gap_has_lower_limit() {
+        // 18 bit periods per frame leave the shortest gap, 17 are too few
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot600, 33_333), Ok(DshotMode::Repeat { gap_bits: REPEAT_MIN_GAP_BITS }));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot600, 33_334), Err(DshotError::UnreachableFrameRate));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot600, 600_000), Err(DshotError::UnreachableFrameRate));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot600, 1_000_000), Err(DshotError::UnreachableFrameRate));
+    }
+
+    #[test]
+    fn repeat_rejects_unreachable_rates() {
+        for speed in SPEEDS {
+            assert_eq!(DshotMode::repeat(speed, 0), Err(DshotError::UnreachableFrameRate));
+        }
+        // The gap no longer fits the 16-bit counter
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot1200, 1), Err(DshotError::UnreachableFrameRate));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot150, 2), Err(DshotError::UnreachableFrameRate));
+        assert_eq!(DshotMode::repeat(DshotSpeed::Dshot150, 3), Ok(DshotMode::Repeat { gap_bits: 49_984 }));
+    }
+
+    #[test]
+    fn repeat_words_carry_gap_loop_length() {
+        let mode = DshotMode::Repeat { gap_bits: 59 };
+        assert_eq!(mode.tx_word(0x1234), (57 << 16) | 0x1234);
+        assert_eq!(DshotMode::Repeat { gap_bits: REPEAT_MIN_GAP_BITS }.tx_word(0x1234), 0x1234);
+    }
+
+    #[test]
+    fn command_frames_request_telemetry() {
+        assert_eq!(DshotValue::Command(0).frame(false), 0x0011);
+        assert_eq!(DshotValue::Command(1).frame(false), 0x0033);
+        assert_eq!(DshotValue::Command(1).frame(true), 0x0033);
+        assert_eq!(DshotCommand::SpinDirectionReversed.frame(), 0x02B9);
+        assert_eq!(DshotCommand::SpinDirectionReversed.frame(), encoder::reverse(true));
+        assert_eq!(DshotCommand::SpinDirectionNormal.frame(), encoder::reverse(false));
+    }
+
+    #[test]
+    fn command_frames_are_clamped() {
+        assert_eq!(DshotValue::Command(48).frame(true), DshotValue::Command(47).frame(true));
+        assert_eq!(DshotValue::Command(255).frame(true), DshotValue::Command(47).frame(true));
+    }
+
+    #[test]
+    fn clock_dividers_at_125_mhz() {
+        let dividers = [(104, 43), (52, 21), (26, 11), (13, 5)];
+        let bidirectional = [(83, 85), (41, 171), (20, 213), (10, 107)];
+        for (i, speed) in SPEEDS.into_iter().enumerate() {
+            assert_eq!(speed.clock_divider(125_000_000, DshotMode::Normal), Ok(dividers[i]));
+            assert_eq!(speed.clock_divider(125_000_000, DshotMode::Repeat { gap_bits: 20 }), Ok(dividers[i]));
+            assert_eq!(speed.clock_divider(125_000_000, DshotMode::Bidirectional), Ok(bidirectional[i]));
+        }
+    }
+
+    #[test]
+    fn clock_dividers_at_133_mhz() {
+        let dividers = [(110, 213), (55, 107), (27, 181), (13, 219)];
+        let bidirectional = [(88, 171), (44, 85), (22, 43), (11, 21)];
+        for (i, speed) in SPEEDS.into_iter().enumerate() {
+            assert_eq!(speed.clock_divider(133_000_000, DshotMode::Normal), Ok(dividers[i]));
+            assert_eq!(speed.clock_divider(133_000_000, DshotMode::Bidirectional), Ok(bidirectional[i]));
+        }
+    }
+
+    #[test]
+    fn clock_divider_rounds_to_nearest() {
+        // 6666.67 / 256 rounds up to 6667 / 256, 7093.33 / 256 rounds down to 7093 / 256
+        assert_eq!(DshotSpeed::Dshot600.clock_divider(125_000_000, DshotMode::Normal), Ok((26, 11)));
+        assert_eq!(DshotSpeed::Dshot600.clock_divider(133_000_000, DshotMode::Normal), Ok((27, 181)));
+        // Whole dividers have no fraction
+        assert_eq!(DshotSpeed::Dshot600.clock_divider(150_000_000, DshotMode::Bidirectional), Ok((25, 0)));
+    }
+
+    #[test]
+    fn parallel_clock_dividers() {
+        let dividers = [(52, 21), (26, 11), (13, 5)];
+        for (i, speed) in SPEEDS[..3].iter().enumerate() {
+            assert_eq!(speed.parallel_clock_divider(125_000_000), Ok(dividers[i]));
+        }
+        assert_eq!(DshotSpeed::Dshot1200.parallel_clock_divider(150_000_000), Ok((7, 208)));
+        assert_eq!(DshotSpeed::Dshot1200.parallel_clock_divider(15_000_000), Err(DshotError::UnreachableSpeed));
+    }
+
+    #[test]
+    fn clock_divider_rejects_unreachable_speeds() {
+        // Below a divider of 1
+        assert_eq!(DshotSpeed::Dshot600.clock_divider(1_000_000, DshotMode::Normal), Err(DshotError::UnreachableSpeed));
+        assert_eq!(DshotSpeed::Dshot1200.clock_divider(9_000_000, DshotMode::Normal), Err(DshotError::UnreachableSpeed));
+        // Small fractional dividers stretch PIO cycles too much
+        assert_eq!(DshotSpeed::Dshot1200.clock_divider(10_000_000, DshotMode::Normal), Err(DshotError::UnreachableSpeed));
+        assert_eq!(DshotSpeed::Dshot1200.clock_divider(15_000_000, DshotMode::Bidirectional), Err(DshotError::UnreachableSpeed));
+    }
+
+    #[test]
+    fn throttle_frames_follow_telemetry() {
+        assert_eq!(DshotValue::Throttle(1046).frame(false), encoder::throttle_clamp(1046, false));
+        assert_eq!(DshotValue::Throttle(1046).frame(true), encoder::throttle_clamp(1046, true));
+        assert_eq!(DshotValue::Throttle(0).frame(false), encoder::throttle_minimum(false));
+    }
+}
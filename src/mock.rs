@@ -0,0 +1,193 @@
+//! Stand-ins for the PIO backends, for testing on the host.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use super::encoder::{THROTTLE_MAX, THROTTLE_MIN};
+use super::fifo::DshotFifo;
+use super::telemetry::Telemetry;
+use super::{DshotCommand, DshotLocate, DshotMode, DshotPioTrait, DshotPioTry, DshotSync, DshotValue};
+
+/// FIFOs of a driver, recording every word pushed as `(motor, word)`
+pub(crate) struct MockFifo {
+    mode: DshotMode,
+    pub pushed: Vec<(usize, u32)>,
+    /// Words waiting in the RX FIFO of each motor
+    pub rx: [VecDeque<u32>; 4],
+}
+
+impl MockFifo {
+    pub fn new(mode: DshotMode) -> MockFifo {
+        MockFifo { mode, pushed: Vec::new(), rx: Default::default() }
+    }
+}
+
+impl DshotFifo for MockFifo {
+    fn mode(&self) -> DshotMode {
+        self.mode
+    }
+
+    fn is_full(&mut self, _motor: usize) -> bool {
+        false
+    }
+
+    fn push(&mut self, motor: usize, word: u32) {
+        self.pushed.push((motor, word));
+    }
+
+    fn pull(&mut self, motor: usize) -> Option<u32> {
+        self.rx[motor].pop_front()
+    }
+}
+
+/// Value and telemetry bit sent to a single motor, `None` if it was left alone
+pub(crate) type Sent<const N: usize> = [Option<(DshotValue, bool)>; N];
+
+/// Motor stop, as sent to a single motor
+pub(crate) const STOP: Option<(DshotValue, bool)> = Some((DshotValue::Command(DshotCommand::MotorStop as u8), false));
+
+/// Throttle sent to a single motor, without requesting telemetry
+pub(crate) fn throttle(throttle: u16) -> Option<(DshotValue, bool)> {
+    Some((DshotValue::Throttle(throttle), false))
+}
+
+/// Special command sent to a single motor, which always requests telemetry
+pub(crate) fn command(command: DshotCommand) -> Option<(DshotValue, bool)> {
+    Some((command.into(), true))
+}
+
+/// Driver recording what each call sent to each motor
+pub(crate) struct MockDshot<const N: usize> {
+    pub sent: Vec<Sent<N>>,
+    /// Motors whose TX FIFO is full, dropping frames sent without blocking
+    pub full: [bool; N],
+    /// Number of times the driver was halted and started again
+    pub restarts: usize,
+    halted: bool,
+    /// PIO block the driver claims to be on, where motor `i` is on state machine `i`
+    block: usize,
+    /// Replies returned by `erpm` and `telemetry`
+    pub erpm: [Option<u32>; N],
+    pub telemetry: [Option<Telemetry>; N],
+    /// Halts and starts of several drivers in order, as `(block, started)`
+    sync_log: Option<SyncLog>,
+}
+
+/// Halts and starts shared between drivers, see `MockDshot::logging_to`
+pub(crate) type SyncLog = Rc<RefCell<Vec<(usize, bool)>>>;
+
+impl<const N: usize> MockDshot<N> {
+    pub fn new() -> MockDshot<N> {
+        MockDshot {
+            sent: Vec::new(),
+            full: [false; N],
+            restarts: 0,
+            halted: false,
+            block: 0,
+            erpm: [None; N],
+            telemetry: [None; N],
+            sync_log: None,
+        }
+    }
+
+    pub fn on_block(self, block: usize) -> MockDshot<N> {
+        MockDshot { block, ..self }
+    }
+
+    /// Record halts and starts in a log shared with other drivers
+    pub fn logging_to(self, log: &SyncLog) -> MockDshot<N> {
+        MockDshot { sync_log: Some(log.clone()), ..self }
+    }
+
+    /// What the last call sent to each motor
+    pub fn last(&self) -> Sent<N> {
+        *self.sent.last().expect("nothing was sent")
+    }
+
+    fn record(&mut self, values: [DshotValue; N], telemetry: [bool; N]) {
+        assert!(!self.halted, "frame sent while halted");
+        self.sent.push(core::array::from_fn(|i| Some((values[i], telemetry[i]))));
+    }
+}
+
+impl<const N: usize> DshotPioTrait<N> for MockDshot<N> {
+    fn command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) {
+        self.record(command, telemetry);
+    }
+
+    fn send_command(&mut self, command: [Option<DshotCommand>; N]) {
+        self.sent.push(command.map(|command| command.map(|command| (command.into(), true))));
+    }
+
+    fn reverse(&mut self, reverse: [bool; N]) {
+        let command = reverse.map(|reverse| match reverse {
+            true => DshotCommand::SpinDirectionReversed.into(),
+            false => DshotCommand::SpinDirectionNormal.into(),
+        });
+        self.record(command, [true; N]);
+    }
+
+    fn extended_telemetry(&mut self, enable: [bool; N]) {
+        let command = enable.map(|enable| match enable {
+            true => DshotCommand::ExtendedTelemetryEnable.into(),
+            false => DshotCommand::ExtendedTelemetryDisable.into(),
+        });
+        self.record(command, [true; N]);
+    }
+
+    fn throttle_clamp(&mut self, throttle: [u16; N]) {
+        self.throttle_clamp_telemetry(throttle, [false; N]);
+    }
+
+    fn throttle_clamp_telemetry(&mut self, throttle: [u16; N], telemetry: [bool; N]) {
+        let throttle = throttle.map(|throttle| DshotValue::Throttle(throttle.clamp(THROTTLE_MIN, THROTTLE_MAX)));
+        self.record(throttle, telemetry);
+    }
+
+    fn throttle_minimum(&mut self) {
+        self.record([DshotValue::Throttle(THROTTLE_MIN); N], [false; N]);
+    }
+
+    fn erpm(&mut self) -> [Option<u32>; N] {
+        self.erpm
+    }
+
+    fn telemetry(&mut self) -> [Option<Telemetry>; N] {
+        self.telemetry
+    }
+}
+
+impl<const N: usize> DshotPioTry<N> for MockDshot<N> {
+    fn try_command(&mut self, command: [DshotValue; N], telemetry: [bool; N]) -> [bool; N] {
+        let full = self.full;
+        self.sent.push(core::array::from_fn(|i| (!full[i]).then_some((command[i], telemetry[i]))));
+        full
+    }
+}
+
+impl<const N: usize> DshotSync for MockDshot<N> {
+    fn halt(&mut self) {
+        self.halted = true;
+        if let Some(log) = &self.sync_log {
+            log.borrow_mut().push((self.block, false));
+        }
+    }
+
+    fn start(&mut self) {
+        assert!(self.halted, "started without halting");
+        self.halted = false;
+        self.restarts += 1;
+        if let Some(log) = &self.sync_log {
+            log.borrow_mut().push((self.block, true));
+        }
+    }
+}
+
+impl<const N: usize> DshotLocate for MockDshot<N> {
+    fn locate(&self, motor: usize) -> (usize, usize) {
+        assert!(motor < N, "Motor index out of range");
+        (self.block, motor)
+    }
+}
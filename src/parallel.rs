@@ -0,0 +1,94 @@
+//! Bit transposition for driving several motors from a single state machine.
+//!
+//! The parallel program outputs one bit to each of up to 8 consecutive pins at
+//! a time, so the N frames sent together are transposed into 16 N-bit words, one
+//! for every bit position of the frames, starting with the most significant bit.
+
+/// Largest number of motors a single state machine can drive
+pub const MAX_MOTORS: usize = 8;
+
+/// Number of FIFO words the parallel program consumes per frame
+pub(crate) const FRAME_WORDS: usize = 5;
+
+/// Depth of the TX FIFO, joined with the unused RX FIFO
+#[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+pub(crate) const FIFO_DEPTH: usize = 8;
+
+/// Number of PIO cycles the parallel program uses to transmit a single bit
+pub(crate) const CYCLES_PER_BIT: u32 = 16;
+
+/// Transpose up to 8 frames into 16 words, where bit `m` of word `i` is bit `15 - i` of frame `m`
+pub fn transpose<const N: usize>(frames: [u16; N]) -> [u8; 16] {
+    let mut words = [0; 16];
+    for (motor, frame) in frames.into_iter().enumerate().take(MAX_MOTORS) {
+        for (i, word) in words.iter_mut().enumerate() {
+            *word |= (((frame >> (15 - i)) & 1) as u8) << motor;
+        }
+    }
+    words
+}
+
+/// Pack transposed words four to a FIFO word, with the first word in the lowest byte
+pub fn pack(words: [u8; 16]) -> [u32; 4] {
+    core::array::from_fn(|i| u32::from_le_bytes([words[4 * i], words[4 * i + 1], words[4 * i + 2], words[4 * i + 3]]))
+}
+
+/// FIFO words for a single parallel frame. The first word masks which motors get a
+/// frame at all, and motors given `None` keep their line low for the whole frame.
+#[cfg_attr(not(any(feature = "_embassy", feature = "rp2040-hal", feature = "rp235x-hal")), allow(dead_code))]
+pub(crate) fn frame_words<const N: usize>(frames: [Option<u16>; N]) -> [u32; FRAME_WORDS] {
+    let mask = frames
+        .iter()
+        .enumerate()
+        .filter(|(_, frame)| frame.is_some())
+        .fold(0, |mask, (motor, _)| mask | 1 << motor);
+    let data = pack(transpose(frames.map(|frame| frame.unwrap_or(0))));
+
+    [mask, data[0], data[1], data[2], data[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transposes_bits_msb_first() {
+        let words = transpose([0x8001, 0x0000, 0xFFFF]);
+        assert_eq!(words[0], 0b101);
+        assert_eq!(words[1..15], [0b100; 14]);
+        assert_eq!(words[15], 0b101);
+
+        // Bit `15 - i` of motor `m` ends up in bit `m` of word `i`
+        let frames = [0x1234, 0xABCD, 0x0F0F, 0xF0F0, 0x5555, 0xAAAA, 0x0001, 0x8000];
+        let words = transpose(frames);
+        for (motor, frame) in frames.into_iter().enumerate() {
+            for (i, word) in words.into_iter().enumerate() {
+                assert_eq!((word >> motor) & 1, ((frame >> (15 - i)) & 1) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn transpose_ignores_motors_beyond_eight() {
+        let mut frames = [0; 9];
+        frames[8] = 0xFFFF;
+        assert_eq!(transpose(frames), [0; 16]);
+    }
+
+    #[test]
+    fn packs_first_word_lowest() {
+        let words = core::array::from_fn(|i| i as u8);
+        assert_eq!(pack(words), [0x0302_0100, 0x0706_0504, 0x0B0A_0908, 0x0F0E_0D0C]);
+    }
+
+    #[test]
+    fn frame_words_mask_motors_given_some() {
+        let words = frame_words([Some(0xFFFF), None, Some(0x8000)]);
+        assert_eq!(words[0], 0b101);
+        assert_eq!(words[1], 0x0101_0105);
+        assert_eq!(words[2..], [0x0101_0101; 3]);
+
+        // Motors given `None` keep their line low
+        assert_eq!(frame_words([None::<u16>; 4]), [0; FRAME_WORDS]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Per-motor throttle ranges.
+//!
+//! Rather than raw throttle values, motors are given a normalized throttle
+//! between 0 and `FULL_THROTTLE`, which is mapped onto the range of each motor,
+//! from its idle throttle up to its throttle cap, and then sent through a
+//! `CommandSequencer`. The spin direction of each motor is part of its range,
+//! and is queued on the sequencer as spin direction commands whenever it
+//! changed, once the motor is given zero throttle. The commands are not saved
+//! in the ESC settings, so they are sent again after the ESCs are power cycled.
+
+use super::encoder::{THROTTLE_MAX, THROTTLE_MIN};
+use super::sequencer::CommandSequencer;
+use super::{DshotCommand, DshotPioTrait};
+
+/// Full throttle, as normalized throttle and in the configuration of a motor's range.
+/// Both are in hundredths of a percent.
+pub const FULL_THROTTLE: u16 = 10_000;
+
+/// Throttle range and spin direction of a single motor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MotorRange {
+    /// Throttle at zero normalized throttle, in hundredths of a percent of the full range
+    pub idle: u16,
+    /// Throttle at full normalized throttle, in hundredths of a percent of the full range
+    pub max: u16,
+    /// Whether the motor spins in reverse
+    pub reversed: bool,
+}
+
+impl Default for MotorRange {
+    fn default() -> Self {
+        MotorRange { idle: 0, max: FULL_THROTTLE, reversed: false }
+    }
+}
+
+impl MotorRange {
+    /// Raw throttle, between 48 and 2047, for a normalized throttle between 0 and `FULL_THROTTLE`
+    pub fn raw(&self, throttle: u16) -> u16 {
+        let idle = self.idle.min(FULL_THROTTLE) as u32;
+        let max = self.max.clamp(self.idle.min(FULL_THROTTLE), FULL_THROTTLE) as u32;
+        let throttle = throttle.min(FULL_THROTTLE) as u32;
+
+        let fraction = idle + (max - idle) * throttle / FULL_THROTTLE as u32;
+        THROTTLE_MIN + ((THROTTLE_MAX - THROTTLE_MIN) as u32 * fraction / FULL_THROTTLE as u32) as u16
+    }
+
+    /// Spin direction command for this motor
+    pub fn direction(&self) -> DshotCommand {
+        match self.reversed {
+            true => DshotCommand::SpinDirectionReversed,
+            false => DshotCommand::SpinDirectionNormal,
+        }
+    }
+}
+
+/// Throttle ranges of N motors, see the module documentation
+pub struct ThrottleRanges<const N: usize> {
+    ranges: [MotorRange; N],
+    /// Spin direction last sent to each motor, `None` if none was yet
+    applied: [Option<bool>; N],
+}
+
+impl<const N: usize> Default for ThrottleRanges<N> {
+    fn default() -> Self {
+        Self::new([MotorRange::default(); N])
+    }
+}
+
+impl<const N: usize> ThrottleRanges<N> {
+    pub fn new(ranges: [MotorRange; N]) -> ThrottleRanges<N> {
+        ThrottleRanges { ranges, applied: [None; N] }
+    }
+
+    /// Range of a single motor
+    pub fn range(&self, motor: usize) -> MotorRange {
+        self.ranges[motor]
+    }
+
+    /// Change the range of a single motor. A changed spin direction is queued by `throttle`
+    /// once the motor is given zero throttle, as ESCs only act on it while the motor is stopped.
+    pub fn set_range(&mut self, motor: usize, range: MotorRange) {
+        self.ranges[motor] = range;
+    }
+
+    /// Queue the spin direction of every motor again from `throttle`, e.g. after the
+    /// ESCs were power cycled and forgot it
+    pub fn resend_directions(&mut self) {
+        self.applied = [None; N];
+    }
+
+    /// Raw throttle of each motor, for normalized throttles between 0 and `FULL_THROTTLE`
+    pub fn raw(&self, throttle: [u16; N]) -> [u16; N] {
+        core::array::from_fn(|motor| self.ranges[motor].raw(throttle[motor]))
+    }
+
+    /// Set the normalized throttle of each motor, between 0 and `FULL_THROTTLE`, through
+    /// `CommandSequencer::update_throttle`, given the current time in microseconds. Motors given
+    /// zero throttle whose spin direction was not sent yet have it queued on the sequencer,
+    /// unless their queue is full, and are sent their queued commands rather than their throttle
+    /// until those are done. Returns the raw throttle of each motor.
+    pub fn throttle<const C: usize>(
+        &mut self,
+        dshot: &mut impl DshotPioTrait<N>,
+        sequencer: &mut CommandSequencer<N, C>,
+        throttle: [u16; N],
+        now_us: u64,
+    ) -> [u16; N] {
+        let motors = self.ranges.iter().zip(&mut self.applied).zip(throttle).enumerate();
+        for (motor, ((range, applied), throttle)) in motors {
+            let pending = *applied != Some(range.reversed);
+            if pending && throttle == 0 && sequencer.push(motor, range.direction()).is_ok() {
+                *applied = Some(range.reversed);
+            }
+        }
+
+        let raw = self.raw(throttle);
+        sequencer.update_throttle(dshot, raw, now_us);
+        raw
+    }
+
+    /// Queue the spin direction command of each motor right away, whatever its throttle.
+    /// Gives back the command of the first motor whose queue is full, in which case no motor
+    /// has its command queued.
+    pub fn queue_directions<const C: usize>(&mut self, sequencer: &mut CommandSequencer<N, C>) -> Result<(), DshotCommand> {
+        if let Some(motor) = (0..N).find(|&motor| sequencer.is_full(motor)) {
+            return Err(self.ranges[motor].direction());
+        }
+        for (motor, range) in self.ranges.iter().enumerate() {
+            sequencer.push(motor, range.direction())?;
+        }
+        self.mark_applied();
+        Ok(())
+    }
+
+    fn mark_applied(&mut self) {
+        self.applied = self.ranges.map(|range| Some(range.reversed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{command, throttle, MockDshot};
+
+    #[test]
+    fn maps_onto_motor_range() {
+        let range = MotorRange { idle: 500, max: 8000, reversed: false };
+        assert_eq!(range.raw(0), THROTTLE_MIN + 99);
+        assert_eq!(range.raw(FULL_THROTTLE), THROTTLE_MIN + 1599);
+        assert_eq!(range.raw(u16::MAX), THROTTLE_MIN + 1599);
+        assert_eq!(MotorRange::default().raw(FULL_THROTTLE), THROTTLE_MAX);
+    }
+
+    #[test]
+    fn sends_directions_before_first_throttle() {
+        let mut ranges = ThrottleRanges::new([MotorRange::default(), MotorRange { reversed: true, ..Default::default() }]);
+        let mut sequencer = CommandSequencer::<2>::new();
+        let mut dshot = MockDshot::new();
+
+        let expected = [command(DshotCommand::SpinDirectionNormal), command(DshotCommand::SpinDirectionReversed)];
+        for frame in 0..6 {
+            ranges.throttle(&mut dshot, &mut sequencer, [0; 2], frame * 1_000);
+            assert_eq!(dshot.last(), expected);
+        }
+        ranges.throttle(&mut dshot, &mut sequencer, [0; 2], 6_000);
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MIN); 2]);
+
+        // Directions are only sent once
+        ranges.throttle(&mut dshot, &mut sequencer, [0; 2], 7_000);
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MIN); 2]);
+    }
+
+    #[test]
+    fn set_range_sends_changed_direction_once_stopped() {
+        let mut ranges = ThrottleRanges::new([MotorRange::default(); 2]);
+        let mut sequencer = CommandSequencer::<2>::new();
+        let mut dshot = MockDshot::new();
+        for frame in 0..7 {
+            ranges.throttle(&mut dshot, &mut sequencer, [0; 2], frame * 1_000);
+        }
+
+        // Not sent while the motor is spinning
+        ranges.set_range(1, MotorRange { reversed: true, ..Default::default() });
+        ranges.throttle(&mut dshot, &mut sequencer, [FULL_THROTTLE; 2], 7_000);
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MAX); 2]);
+
+        // The other motor keeps its throttle while the direction is sent
+        for frame in 8..14 {
+            ranges.throttle(&mut dshot, &mut sequencer, [FULL_THROTTLE, 0], frame * 1_000);
+            assert_eq!(dshot.last(), [throttle(THROTTLE_MAX), command(DshotCommand::SpinDirectionReversed)]);
+        }
+        ranges.throttle(&mut dshot, &mut sequencer, [FULL_THROTTLE, 0], 14_000);
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MAX), throttle(THROTTLE_MIN)]);
+
+        ranges.resend_directions();
+        ranges.throttle(&mut dshot, &mut sequencer, [0; 2], 15_000);
+        assert_eq!(dshot.last(), [command(DshotCommand::SpinDirectionNormal), command(DshotCommand::SpinDirectionReversed)]);
+    }
+
+    #[test]
+    fn queue_directions_is_all_or_nothing() {
+        let mut ranges = ThrottleRanges::new([MotorRange::default(), MotorRange { reversed: true, ..Default::default() }]);
+        let mut sequencer = CommandSequencer::<2, 1>::new();
+        sequencer.push(1, DshotCommand::Beep1).unwrap();
+        assert_eq!(ranges.queue_directions(&mut sequencer), Err(DshotCommand::SpinDirectionReversed));
+        assert!(!sequencer.is_full(0));
+
+        let mut sequencer = CommandSequencer::<2, 1>::new();
+        assert_eq!(ranges.queue_directions(&mut sequencer), Ok(()));
+        assert!(sequencer.is_full(0) && sequencer.is_full(1));
+
+        // Queued directions are not queued again by throttle
+        let mut dshot = MockDshot::new();
+        for frame in 0..7 {
+            ranges.throttle(&mut dshot, &mut sequencer, [0; 2], frame * 1_000);
+        }
+        assert!(sequencer.is_finished(7_000));
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MIN); 2]);
+    }
+}
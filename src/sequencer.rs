@@ -0,0 +1,246 @@
+//! Sending special commands the way ESCs expect them.
+//!
+//! Some commands must be received several times in a row, and others need
+//! the ESC to be left alone for a while afterwards. The sequencer queues
+//! commands per motor, and sends one frame per motor on every `update`,
+//! filling any gaps with motor stop frames, or with throttle through
+//! `update_throttle`.
+
+use super::encoder::{THROTTLE_MAX, THROTTLE_MIN};
+use super::{DshotCommand, DshotPioTrait, DshotValue};
+
+/// Commands queued for, and being sent to, a single motor
+#[derive(Clone, Copy)]
+struct MotorQueue<const C: usize> {
+    commands: [DshotCommand; C],
+    head: usize,
+    len: usize,
+    repeats_left: u8,
+    wait_until_us: u64,
+}
+
+impl<const C: usize> MotorQueue<C> {
+    const fn new() -> MotorQueue<C> {
+        MotorQueue {
+            commands: [DshotCommand::MotorStop; C],
+            head: 0,
+            len: 0,
+            repeats_left: 0,
+            wait_until_us: 0,
+        }
+    }
+
+    fn push(&mut self, command: DshotCommand) -> Result<(), DshotCommand> {
+        if self.len == C {
+            return Err(command);
+        }
+        self.commands[(self.head + self.len) % C] = command;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Next value to send to this motor, `None` once it has nothing left to send
+    fn next(&mut self, now_us: u64) -> Option<(DshotValue, bool)> {
+        if self.is_finished(now_us) {
+            return None;
+        }
+        if self.len == 0 || now_us < self.wait_until_us {
+            return Some((DshotCommand::MotorStop.into(), false));
+        }
+
+        let command = self.commands[self.head];
+        if self.repeats_left == 0 {
+            self.repeats_left = command.repeats();
+        }
+
+        // Move on to the next command once this one has been sent enough times
+        self.repeats_left -= 1;
+        if self.repeats_left == 0 {
+            self.head = (self.head + 1) % C;
+            self.len -= 1;
+            self.wait_until_us = now_us + command.delay_after_us() as u64;
+        }
+
+        Some((command.into(), true))
+    }
+
+    fn is_finished(&self, now_us: u64) -> bool {
+        self.len == 0 && now_us >= self.wait_until_us
+    }
+}
+
+/// Queues special commands for N motors, each holding up to C pending commands
+pub struct CommandSequencer<const N: usize, const C: usize = 8> {
+    queues: [MotorQueue<C>; N],
+}
+
+impl<const N: usize, const C: usize> Default for CommandSequencer<N, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const C: usize> CommandSequencer<N, C> {
+    pub const fn new() -> CommandSequencer<N, C> {
+        CommandSequencer { queues: [MotorQueue::new(); N] }
+    }
+
+    /// Whether the queue of a single motor is full
+    pub fn is_full(&self, motor: usize) -> bool {
+        self.queues[motor].len == C
+    }
+
+    /// Queue a command for a single motor. Gives back the command if that motor's queue is full.
+    pub fn push(&mut self, motor: usize, command: DshotCommand) -> Result<(), DshotCommand> {
+        self.queues[motor].push(command)
+    }
+
+    /// Queue a command for all motors. Gives back the command if any motor's queue is full,
+    /// in which case no motor has it queued.
+    pub fn push_all(&mut self, command: DshotCommand) -> Result<(), DshotCommand> {
+        if (0..N).any(|motor| self.is_full(motor)) {
+            return Err(command);
+        }
+        for queue in self.queues.iter_mut() {
+            queue.push(command)?;
+        }
+        Ok(())
+    }
+
+    /// Send the next frame to each motor, given the current time in microseconds. Should be
+    /// called at the regular DShot update rate, without sending any other frames in between.
+    /// Returns `true` once every queued command has been sent and its delay has passed.
+    pub fn update(&mut self, dshot: &mut impl DshotPioTrait<N>, now_us: u64) -> bool {
+        self.send(dshot, [DshotCommand::MotorStop.into(); N], now_us)
+    }
+
+    /// Like `update`, but motors with nothing left to send are sent their throttle,
+    /// clamped between 48 and 2047, rather than motor stop
+    pub fn update_throttle(&mut self, dshot: &mut impl DshotPioTrait<N>, throttle: [u16; N], now_us: u64) -> bool {
+        let idle = throttle.map(|throttle| DshotValue::Throttle(throttle.clamp(THROTTLE_MIN, THROTTLE_MAX)));
+        self.send(dshot, idle, now_us)
+    }
+
+    /// Send the next frame to each motor, or `idle` to motors with nothing left to send
+    fn send(&mut self, dshot: &mut impl DshotPioTrait<N>, idle: [DshotValue; N], now_us: u64) -> bool {
+        let mut values = idle;
+        let mut telemetry = [false; N];
+        for (motor, queue) in self.queues.iter_mut().enumerate() {
+            if let Some(next) = queue.next(now_us) {
+                (values[motor], telemetry[motor]) = next;
+            }
+        }
+        dshot.command(values, telemetry);
+
+        self.is_finished(now_us)
+    }
+
+    /// Whether every queued command has been sent and its delay has passed
+    pub fn is_finished(&self, now_us: u64) -> bool {
+        self.queues.iter().all(|queue| queue.is_finished(now_us))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{command, throttle, MockDshot, STOP};
+
+    #[test]
+    fn repeats_commands_as_required() {
+        let mut sequencer = CommandSequencer::<2>::new();
+        let mut dshot = MockDshot::new();
+        sequencer.push(0, DshotCommand::SpinDirectionReversed).unwrap();
+        sequencer.push(1, DshotCommand::Led0On).unwrap();
+
+        for frame in 0..6 {
+            sequencer.update(&mut dshot, frame * 1_000);
+            let led = match frame {
+                0 => command(DshotCommand::Led0On),
+                _ => STOP,
+            };
+            assert_eq!(dshot.last(), [command(DshotCommand::SpinDirectionReversed), led]);
+        }
+        assert!(sequencer.update(&mut dshot, 6_000));
+        assert_eq!(dshot.last(), [STOP, STOP]);
+    }
+
+    #[test]
+    fn waits_after_beep() {
+        let mut sequencer = CommandSequencer::<1>::new();
+        let mut dshot = MockDshot::new();
+        sequencer.push(0, DshotCommand::Beep1).unwrap();
+        sequencer.push(0, DshotCommand::Beep2).unwrap();
+
+        assert!(!sequencer.update(&mut dshot, 0));
+        assert_eq!(dshot.last(), [command(DshotCommand::Beep1)]);
+
+        // Motor stop is sent for 260 ms after the first beep
+        assert!(!sequencer.update(&mut dshot, 1_000));
+        assert_eq!(dshot.last(), [STOP]);
+        assert!(!sequencer.update(&mut dshot, 259_999));
+        assert_eq!(dshot.last(), [STOP]);
+
+        assert!(!sequencer.update(&mut dshot, 260_000));
+        assert_eq!(dshot.last(), [command(DshotCommand::Beep2)]);
+        assert!(!sequencer.is_finished(519_999));
+        assert!(sequencer.is_finished(520_000));
+    }
+
+    #[test]
+    fn fills_idle_motors_with_motor_stop() {
+        let mut sequencer = CommandSequencer::<3>::new();
+        let mut dshot = MockDshot::new();
+        assert!(sequencer.is_finished(0));
+        assert!(sequencer.update(&mut dshot, 0));
+        assert_eq!(dshot.last(), [STOP; 3]);
+
+        sequencer.push(1, DshotCommand::Beep3).unwrap();
+        assert!(!sequencer.is_finished(0));
+        sequencer.update(&mut dshot, 0);
+        assert_eq!(dshot.last(), [STOP, command(DshotCommand::Beep3), STOP]);
+    }
+
+    #[test]
+    fn fills_idle_motors_with_throttle() {
+        let mut sequencer = CommandSequencer::<2>::new();
+        let mut dshot = MockDshot::new();
+        sequencer.push(0, DshotCommand::Beep1).unwrap();
+
+        sequencer.update_throttle(&mut dshot, [500, 3000], 0);
+        assert_eq!(dshot.last(), [command(DshotCommand::Beep1), throttle(THROTTLE_MAX)]);
+
+        // Motor stop is still sent while waiting after a command
+        sequencer.update_throttle(&mut dshot, [500, 600], 1_000);
+        assert_eq!(dshot.last(), [STOP, throttle(600)]);
+        assert!(sequencer.update_throttle(&mut dshot, [500, 600], 260_000));
+        assert_eq!(dshot.last(), [throttle(500), throttle(600)]);
+    }
+
+    #[test]
+    fn push_gives_back_command_when_full() {
+        let mut sequencer = CommandSequencer::<1, 2>::new();
+        sequencer.push(0, DshotCommand::Beep1).unwrap();
+        sequencer.push(0, DshotCommand::Beep2).unwrap();
+        assert_eq!(sequencer.push(0, DshotCommand::Beep3), Err(DshotCommand::Beep3));
+    }
+
+    #[test]
+    fn push_all_is_all_or_nothing() {
+        let mut sequencer = CommandSequencer::<2, 1>::new();
+        let mut dshot = MockDshot::new();
+        sequencer.push(1, DshotCommand::Beep1).unwrap();
+        assert_eq!(sequencer.push_all(DshotCommand::Beep2), Err(DshotCommand::Beep2));
+
+        // Motor 0 did not get the command either
+        sequencer.update(&mut dshot, 0);
+        assert_eq!(dshot.last(), [STOP, command(DshotCommand::Beep1)]);
+        sequencer.update(&mut dshot, 260_000);
+        assert_eq!(dshot.last(), [STOP, STOP]);
+        assert!(sequencer.is_finished(260_000));
+
+        assert_eq!(sequencer.push_all(DshotCommand::Beep2), Ok(()));
+        sequencer.update(&mut dshot, 260_000);
+        assert_eq!(dshot.last(), [command(DshotCommand::Beep2); 2]);
+    }
+}
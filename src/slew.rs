@@ -0,0 +1,176 @@
+//! Limiting how fast the throttle of each motor may change.
+//!
+//! Sudden jumps from idle to full throttle draw large current spikes, which
+//! can brown out supplies and stress the ESCs. The limiter sits in front of
+//! `throttle_clamp`, and moves the throttle of each motor towards the requested
+//! value by at most its rate times the time elapsed since the previous call.
+//! Motors without a rate follow the requested throttle right away.
+
+use super::encoder::{THROTTLE_MAX, THROTTLE_MIN};
+use super::DshotPioTrait;
+
+/// Throttle positions are tracked in millionths of a throttle unit, so that
+/// slow rates still make progress when called at a high update rate
+const SCALE: u64 = 1_000_000;
+
+/// Points in time the limiter can measure elapsed time between
+pub trait Timestamp {
+    /// Time in microseconds since an arbitrary, fixed epoch
+    fn micros(self) -> u64;
+}
+
+impl Timestamp for u64 {
+    fn micros(self) -> u64 {
+        self
+    }
+}
+
+#[cfg(feature = "_embassy")]
+impl Timestamp for embassy_time::Instant {
+    fn micros(self) -> u64 {
+        self.as_micros()
+    }
+}
+
+/// Limits the throttle slew rate of N motors, see the module documentation
+pub struct SlewLimiter<const N: usize> {
+    rate: [Option<u32>; N],
+    position: [u64; N],
+    last_us: Option<u64>,
+}
+
+impl<const N: usize> SlewLimiter<N> {
+    /// Limit each motor to the given rate, in throttle units per second, or leave it unlimited
+    /// if given `None`. Motors start at minimum throttle.
+    pub fn new(rate: [Option<u32>; N]) -> SlewLimiter<N> {
+        SlewLimiter {
+            rate,
+            position: [THROTTLE_MIN as u64 * SCALE; N],
+            last_us: None,
+        }
+    }
+
+    /// Change the rate of each motor, in throttle units per second, `None` leaving it unlimited
+    pub fn set_rate(&mut self, rate: [Option<u32>; N]) {
+        self.rate = rate;
+    }
+
+    /// Move the throttle of each motor towards the requested one, given the current time,
+    /// and return the throttle to apply. Values are clamped between 48 and 2047.
+    pub fn limit(&mut self, throttle: [u16; N], now: impl Timestamp) -> [u16; N] {
+        let now_us = now.micros();
+        let elapsed_us = now_us.saturating_sub(self.last_us.unwrap_or(now_us));
+        self.last_us = Some(now_us);
+
+        for ((position, rate), throttle) in self.position.iter_mut().zip(self.rate).zip(throttle) {
+            let target = throttle.clamp(THROTTLE_MIN, THROTTLE_MAX) as u64 * SCALE;
+            let Some(rate) = rate else {
+                *position = target;
+                continue;
+            };
+            let step = (rate as u64).saturating_mul(elapsed_us);
+            *position = match target > *position {
+                true => target.min(position.saturating_add(step)),
+                false => target.max(position.saturating_sub(step)),
+            };
+        }
+        self.applied()
+    }
+
+    /// Set the throttle for each motor, limited in how fast it changes, given the current time.
+    /// Returns the throttle values actually sent.
+    pub fn throttle_clamp(
+        &mut self,
+        dshot: &mut impl DshotPioTrait<N>,
+        throttle: [u16; N],
+        now: impl Timestamp,
+    ) -> [u16; N] {
+        let throttle = self.limit(throttle, now);
+        dshot.throttle_clamp(throttle);
+        throttle
+    }
+
+    /// Throttle last returned for each motor
+    pub fn applied(&self) -> [u16; N] {
+        self.position.map(|position| ((position + SCALE / 2) / SCALE) as u16)
+    }
+
+    /// Start over from minimum throttle, e.g. after the motors were stopped by other means
+    pub fn reset(&mut self) {
+        self.position = [THROTTLE_MIN as u64 * SCALE; N];
+        self.last_us = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{throttle, MockDshot};
+
+    #[test]
+    fn limits_rising_throttle() {
+        let mut slew = SlewLimiter::new([Some(4000)]);
+        assert_eq!(slew.limit([THROTTLE_MAX], 0), [THROTTLE_MIN]);
+        // 4000 units per second allow 40 units in 10 ms
+        assert_eq!(slew.limit([THROTTLE_MAX], 10_000), [THROTTLE_MIN + 40]);
+        assert_eq!(slew.limit([THROTTLE_MAX], 20_000), [THROTTLE_MIN + 80]);
+        assert_eq!(slew.limit([THROTTLE_MAX], 1_000_000), [THROTTLE_MAX]);
+    }
+
+    #[test]
+    fn limits_falling_throttle() {
+        let mut slew = SlewLimiter::new([Some(4000), None]);
+        slew.limit([1000; 2], 0);
+        assert_eq!(slew.applied(), [THROTTLE_MIN, 1000]);
+        slew.limit([1000; 2], 1_000_000);
+        assert_eq!(slew.applied(), [1000; 2]);
+
+        assert_eq!(slew.limit([0; 2], 1_010_000), [960, THROTTLE_MIN]);
+        assert_eq!(slew.limit([0; 2], 1_500_000), [THROTTLE_MIN; 2]);
+    }
+
+    #[test]
+    fn accumulates_slow_rates() {
+        // One throttle unit per second, a thousandth of a unit per call
+        let mut slew = SlewLimiter::new([Some(1)]);
+        slew.limit([THROTTLE_MAX], 0);
+        for ms in 1..=499 {
+            assert_eq!(slew.limit([THROTTLE_MAX], ms * 1_000), [THROTTLE_MIN]);
+        }
+        assert_eq!(slew.limit([THROTTLE_MAX], 500_000), [THROTTLE_MIN + 1]);
+        assert_eq!(slew.limit([THROTTLE_MAX], 1_500_000), [THROTTLE_MIN + 2]);
+    }
+
+    #[test]
+    fn unlimited_motors_follow_right_away() {
+        let mut slew = SlewLimiter::new([None, Some(0)]);
+        assert_eq!(slew.limit([1500; 2], 0), [1500, THROTTLE_MIN]);
+        assert_eq!(slew.limit([1500; 2], 1_000_000), [1500, THROTTLE_MIN]);
+
+        slew.set_rate([Some(0), None]);
+        assert_eq!(slew.limit([2000; 2], 2_000_000), [1500, 2000]);
+    }
+
+    #[test]
+    fn reset_starts_over_from_minimum() {
+        let mut slew = SlewLimiter::new([Some(4000)]);
+        slew.limit([1000], 0);
+        slew.limit([1000], 1_000_000);
+        assert_eq!(slew.applied(), [1000]);
+
+        slew.reset();
+        assert_eq!(slew.applied(), [THROTTLE_MIN]);
+        // The first call after a reset does not count the time since the previous one
+        assert_eq!(slew.limit([1000], 5_000_000), [THROTTLE_MIN]);
+        assert_eq!(slew.limit([1000], 5_010_000), [THROTTLE_MIN + 40]);
+    }
+
+    #[test]
+    fn sends_limited_throttle() {
+        let mut slew = SlewLimiter::new([Some(4000)]);
+        let mut dshot = MockDshot::new();
+        slew.throttle_clamp(&mut dshot, [THROTTLE_MAX], 0);
+        assert_eq!(slew.throttle_clamp(&mut dshot, [THROTTLE_MAX], 10_000), [THROTTLE_MIN + 40]);
+        assert_eq!(dshot.last(), [throttle(THROTTLE_MIN + 40)]);
+    }
+}
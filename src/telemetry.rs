@@ -0,0 +1,275 @@
+//! Decoding of the telemetry replies sent by ESCs in bidirectional DShot mode.
+//!
+//! The ESC answers every frame with a 21-bit word, where each transition of the
+//! line encodes a one. Undoing that gives a 20-bit GCR word, which holds four
+//! 5-bit symbols making up a 12-bit payload followed by a 4-bit inverted checksum.
+//!
+//! With Extended DShot Telemetry (EDT) enabled, some replies carry a typed value
+//! such as temperature or voltage instead of eRPM, see [`EdtFrame`].
+
+/// Errors that may occur when decoding a telemetry reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TelemetryError {
+    /// A 5-bit group of the reply was not a valid GCR symbol
+    InvalidSymbol,
+    /// The checksum did not match the payload
+    Checksum,
+    /// The reply decoded to an eRPM period of zero
+    ZeroPeriod,
+    /// The reply was an Extended DShot Telemetry frame rather than eRPM
+    Extended,
+}
+
+/// Any reply sent by an ESC in bidirectional mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Telemetry {
+    Erpm(ErpmTelemetry),
+    Extended(EdtFrame),
+}
+
+/// Extended DShot Telemetry frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdtFrame {
+    /// Temperature in degrees Celsius
+    Temperature(u8),
+    /// Voltage in millivolts, with a resolution of 250 mV
+    Voltage(u16),
+    /// Current in amperes
+    Current(u8),
+    Debug1(u8),
+    Debug2(u8),
+    /// Stress level, in the range 0 to 255
+    StressLevel(u8),
+    /// ESC status flags, along with the maximum stress level (0 to 15) seen
+    Status {
+        alert: bool,
+        warning: bool,
+        error: bool,
+        max_stress: u8,
+    },
+}
+
+impl EdtFrame {
+    /// Parse a 12-bit telemetry payload, returning `None` if it holds eRPM rather than EDT
+    pub fn from_payload(payload: u16) -> Option<EdtFrame> {
+        // eRPM payloads have a normalized mantissa, so bit 8 is only
+        // ever clear for EDT frames, or when the exponent is zero
+        if payload & 0x0100 != 0 || payload >> 9 == 0 {
+            return None;
+        }
+
+        let value = (payload & 0xFF) as u8;
+        match payload >> 8 {
+            0x2 => Some(EdtFrame::Temperature(value)),
+            0x4 => Some(EdtFrame::Voltage(value as u16 * 250)),
+            0x6 => Some(EdtFrame::Current(value)),
+            0x8 => Some(EdtFrame::Debug1(value)),
+            0xA => Some(EdtFrame::Debug2(value)),
+            0xC => Some(EdtFrame::StressLevel(value)),
+            _ => Some(EdtFrame::Status {
+                alert: value & 0x80 != 0,
+                warning: value & 0x40 != 0,
+                error: value & 0x20 != 0,
+                max_stress: value & 0x0F,
+            }),
+        }
+    }
+}
+
+/// eRPM telemetry reported by an ESC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErpmTelemetry {
+    period_us: Option<u16>,
+}
+
+impl ErpmTelemetry {
+    /// Period of one electrical revolution in microseconds, or `None` if the motor is stopped
+    pub fn period_us(&self) -> Option<u16> {
+        self.period_us
+    }
+
+    /// Electrical revolutions per minute
+    pub fn erpm(&self) -> u32 {
+        match self.period_us {
+            Some(period) => 60_000_000 / period as u32,
+            None => 0,
+        }
+    }
+
+    /// Mechanical revolutions per minute, for a motor with the given number of pole pairs
+    pub fn rpm(&self, pole_pairs: u8) -> u32 {
+        self.erpm() / pole_pairs.max(1) as u32
+    }
+}
+
+/// Maps a 5-bit GCR symbol to its nibble, `0xFF` marks invalid symbols
+const GCR_DECODE: [u8; 32] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0x09, 0x0A, 0x0B, 0xFF, 0x0D, 0x0E, 0x0F,
+    0xFF, 0xFF, 0x02, 0x03, 0xFF, 0x05, 0x06, 0x07,
+    0xFF, 0x00, 0x08, 0x01, 0xFF, 0x04, 0x0C, 0xFF,
+];
+
+/// Decode a raw 21-bit reply into its 12-bit payload, checking the checksum
+pub fn decode_payload(raw: u32) -> Result<u16, TelemetryError> {
+    // A transition between two samples encodes a one in the GCR word
+    let gcr = (raw ^ (raw >> 1)) & 0xF_FFFF;
+
+    let mut value = 0u16;
+    for i in 0..4 {
+        let nibble = GCR_DECODE[((gcr >> (5 * i)) & 0x1F) as usize];
+        if nibble == 0xFF {
+            return Err(TelemetryError::InvalidSymbol);
+        }
+        value |= (nibble as u16) << (4 * i);
+    }
+
+    // Telemetry checksum is inverted
+    let crc = value ^ (value >> 4) ^ (value >> 8) ^ (value >> 12);
+    if crc & 0x0F != 0x0F {
+        return Err(TelemetryError::Checksum);
+    }
+
+    Ok(value >> 4)
+}
+
+/// Decode a raw 21-bit reply captured by the bidirectional PIO program
+pub fn decode(raw: u32) -> Result<Telemetry, TelemetryError> {
+    let payload = decode_payload(raw)?;
+    match EdtFrame::from_payload(payload) {
+        Some(frame) => Ok(Telemetry::Extended(frame)),
+        None => erpm_from_payload(payload).map(Telemetry::Erpm),
+    }
+}
+
+/// Decode a raw 21-bit reply captured by the bidirectional PIO program into eRPM telemetry
+pub fn decode_erpm(raw: u32) -> Result<ErpmTelemetry, TelemetryError> {
+    match decode(raw)? {
+        Telemetry::Erpm(erpm) => Ok(erpm),
+        Telemetry::Extended(_) => Err(TelemetryError::Extended),
+    }
+}
+
+fn erpm_from_payload(payload: u16) -> Result<ErpmTelemetry, TelemetryError> {
+    // Payload is the eRPM period in microseconds, as eee mmmmmmmmm
+    if payload == 0x0FFF {
+        return Ok(ErpmTelemetry { period_us: None });
+    }
+
+    match (payload & 0x01FF) << (payload >> 9) {
+        0 => Err(TelemetryError::ZeroPeriod),
+        period => Ok(ErpmTelemetry { period_us: Some(period) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Raw 21-bit reply carrying the given 16-bit value, payload and checksum
+    fn encode_value(value: u16) -> u32 {
+        let mut gcr = 0u32;
+        for i in 0..4 {
+            let nibble = (value >> (4 * i)) & 0x0F;
+            let symbol = GCR_DECODE.iter().position(|&decoded| decoded as u16 == nibble).unwrap();
+            gcr |= (symbol as u32) << (5 * i);
+        }
+
+        // Undo the transition encoding, starting from a low line
+        let mut raw = 0u32;
+        let mut line = 0u32;
+        for bit in (0..20).rev() {
+            line ^= (gcr >> bit) & 1;
+            raw |= line << bit;
+        }
+        raw
+    }
+
+    /// Raw 21-bit reply carrying the given 12-bit payload, with a valid checksum
+    fn encode(payload: u16) -> u32 {
+        let crc = !(payload ^ (payload >> 4) ^ (payload >> 8)) & 0x0F;
+        encode_value(payload << 4 | crc)
+    }
+
+    #[test]
+    fn decodes_known_replies() {
+        assert_eq!(decode_payload(0x5_2951), Ok(0x0FFF));
+        assert_eq!(decode_payload(0xE_D273), Ok(0x03E8));
+        assert_eq!(decode_payload(0xE_7123), Ok(0x022D));
+    }
+
+    #[test]
+    fn roundtrips_every_payload() {
+        for payload in 0..=0x0FFF {
+            assert_eq!(decode_payload(encode(payload)), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_symbol() {
+        // A run of zeros decodes to GCR symbol 0, which is not a valid symbol
+        assert_eq!(decode_payload(0), Err(TelemetryError::InvalidSymbol));
+        assert_eq!(decode(encode(0x03E8) & !0x1F), Err(TelemetryError::InvalidSymbol));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let crc = !(0x03E8 ^ (0x03E8 >> 4) ^ (0x03E8 >> 8)) & 0x0F;
+        assert_eq!(decode_payload(encode_value(0x03E8 << 4 | (crc ^ 1))), Err(TelemetryError::Checksum));
+        assert_eq!(decode_erpm(encode_value(0x03E8 << 4 | (crc ^ 1))), Err(TelemetryError::Checksum));
+    }
+
+    #[test]
+    fn decodes_erpm() {
+        // Exponent 1, mantissa 0x1E8: a period of 976 us
+        let erpm = decode_erpm(0xE_D273).unwrap();
+        assert_eq!(erpm.period_us(), Some(976));
+        assert_eq!(erpm.erpm(), 60_000_000 / 976);
+        assert_eq!(erpm.rpm(7), 60_000_000 / 976 / 7);
+    }
+
+    #[test]
+    fn decodes_stopped_motor() {
+        let erpm = decode_erpm(encode(0x0FFF)).unwrap();
+        assert_eq!(erpm.period_us(), None);
+        assert_eq!(erpm.erpm(), 0);
+    }
+
+    #[test]
+    fn rejects_zero_period() {
+        assert_eq!(decode_erpm(encode(0x0000)), Err(TelemetryError::ZeroPeriod));
+    }
+
+    #[test]
+    fn decode_erpm_rejects_edt() {
+        assert_eq!(decode_erpm(encode(0x022D)), Err(TelemetryError::Extended));
+        assert_eq!(decode(encode(0x022D)), Ok(Telemetry::Extended(EdtFrame::Temperature(0x2D))));
+    }
+
+    #[test]
+    fn parses_each_edt_type() {
+        assert_eq!(EdtFrame::from_payload(0x0232), Some(EdtFrame::Temperature(50)));
+        assert_eq!(EdtFrame::from_payload(0x0430), Some(EdtFrame::Voltage(48 * 250)));
+        assert_eq!(EdtFrame::from_payload(0x0612), Some(EdtFrame::Current(18)));
+        assert_eq!(EdtFrame::from_payload(0x0801), Some(EdtFrame::Debug1(1)));
+        assert_eq!(EdtFrame::from_payload(0x0A02), Some(EdtFrame::Debug2(2)));
+        assert_eq!(EdtFrame::from_payload(0x0C80), Some(EdtFrame::StressLevel(128)));
+        assert_eq!(
+            EdtFrame::from_payload(0x0EA7),
+            Some(EdtFrame::Status { alert: true, warning: false, error: true, max_stress: 7 })
+        );
+        assert_eq!(
+            EdtFrame::from_payload(0x0E40),
+            Some(EdtFrame::Status { alert: false, warning: true, error: false, max_stress: 0 })
+        );
+    }
+
+    #[test]
+    fn erpm_payloads_are_not_edt() {
+        // Bit 8 set, or a zero exponent, means eRPM
+        assert_eq!(EdtFrame::from_payload(0x03E8), None);
+        assert_eq!(EdtFrame::from_payload(0x0FFF), None);
+        assert_eq!(EdtFrame::from_payload(0x00FF), None);
+        assert_eq!(EdtFrame::from_payload(0x0000), None);
+    }
+}